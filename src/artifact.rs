@@ -0,0 +1,434 @@
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::rewrite::PathRewriter;
+use crate::util::uses_json_message_format;
+
+/// A file produced by the build, as reported by cargo's `compiler-artifact` message
+#[derive(Debug, Clone, Deserialize)]
+pub struct Artifact {
+    pub manifest_path: PathBuf,
+    pub target: ArtifactTarget,
+    pub profile: ArtifactProfile,
+    pub filenames: Vec<PathBuf>,
+    pub executable: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArtifactTarget {
+    pub name: String,
+    pub kind: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ArtifactProfile {
+    pub test: bool,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+enum Message {
+    CompilerArtifact(Artifact),
+    #[serde(other)]
+    Other,
+}
+
+impl Artifact {
+    pub fn has_kind(&self, kind: &str) -> bool {
+        self.target.kind.iter().any(|k| k == kind)
+    }
+
+    /// Whether this is a `kind` target (`bin` or `example`) that can be run. Test
+    /// harnesses are executables too, but built from the test profile.
+    pub fn is_runnable(&self, kind: &str) -> bool {
+        self.has_kind(kind) && !self.profile.test && self.executable.is_some()
+    }
+
+    /// Files worth copying back: executables and libraries meant to be loaded or linked
+    /// by something other than rustc. Build scripts, rlibs and proc-macros are skipped.
+    pub fn files_to_copy(&self) -> Vec<&Path> {
+        if self.has_kind("custom-build") {
+            return Vec::new();
+        }
+
+        if let Some(executable) = &self.executable {
+            return vec![executable.as_path()];
+        }
+
+        let native_lib = ["cdylib", "dylib", "staticlib"]
+            .iter()
+            .any(|kind| self.has_kind(kind));
+        if !native_lib {
+            return Vec::new();
+        }
+
+        self.filenames
+            .iter()
+            .map(PathBuf::as_path)
+            .filter(|path| {
+                !matches!(
+                    path.extension().and_then(|ext| ext.to_str()),
+                    Some("rlib" | "rmeta")
+                )
+            })
+            .collect()
+    }
+
+    /// Apply `map` to every path of the artifact, dropping the ones it returns `None` for
+    pub fn map_paths(&self, map: impl Fn(&Path) -> Option<PathBuf>) -> Artifact {
+        Artifact {
+            filenames: self.filenames.iter().filter_map(|p| map(p)).collect(),
+            executable: self.executable.as_deref().and_then(&map),
+            ..self.clone()
+        }
+    }
+}
+
+/// Make cargo report artifacts as JSON while still rendering diagnostics for humans.
+///
+/// Returns the adjusted arguments and whether the caller asked for JSON output itself,
+/// in which case the messages have to be passed on.
+pub fn with_artifact_message_format(args: &[String]) -> (Vec<String>, bool) {
+    if uses_json_message_format(args) {
+        return (args.to_vec(), true);
+    }
+
+    // Human readable formats are replaced, keeping `short` diagnostics if requested
+    let mut short = false;
+    let mut final_args = Vec::new();
+    let mut iter = args.iter();
+    while let Some(arg) = iter.next() {
+        if arg == "--" {
+            final_args.push(arg.clone());
+            final_args.extend(iter.by_ref().cloned());
+            break;
+        }
+
+        let format = if arg == "--message-format" {
+            iter.next().cloned()
+        } else if let Some(format) = arg.strip_prefix("--message-format=") {
+            Some(format.to_string())
+        } else {
+            final_args.push(arg.clone());
+            continue;
+        };
+        short |= format.is_some_and(|format| format.contains("short"));
+    }
+
+    let format = if short {
+        "--message-format=json-render-diagnostics,json-diagnostic-short"
+    } else {
+        "--message-format=json-render-diagnostics"
+    };
+    final_args.insert(0, format.to_string());
+
+    (final_args, false)
+}
+
+/// Separates cargo's JSON messages from the rest of the output, collecting artifacts.
+///
+/// Lines starting with `{` are held back until complete and parsed, everything else
+/// is passed through immediately so progress bars keep working. Under a terminal cargo
+/// clears its progress bar with `\r\x1b[K` in front of each message, so a `{` after a
+/// carriage return or escape sequences at the start of a line starts a message too.
+pub struct ArtifactCollector<W: Write> {
+    text: PathRewriter,
+    json: Option<PathRewriter>,
+    out: W,
+    line: Vec<u8>,
+    in_json: bool,
+    at_line_start: bool,
+    escape: Escape,
+    artifacts: Vec<Artifact>,
+}
+
+/// Progress through an ANSI escape sequence at the start of a line
+#[derive(Clone, Copy, PartialEq)]
+enum Escape {
+    None,
+    /// After `ESC`
+    Started,
+    /// Inside a control sequence, after `ESC [`
+    Csi,
+}
+
+impl<W: Write> ArtifactCollector<W> {
+    /// `json` rewrites and passes on the JSON messages, they are swallowed when `None`
+    pub fn new(text: PathRewriter, json: Option<PathRewriter>, out: W) -> Self {
+        ArtifactCollector {
+            text,
+            json,
+            out,
+            line: Vec::new(),
+            in_json: false,
+            at_line_start: true,
+            escape: Escape::None,
+            artifacts: Vec::new(),
+        }
+    }
+
+    pub fn feed(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut text = Vec::new();
+        for &byte in data {
+            if self.in_json {
+                self.line.push(byte);
+                if byte == b'\n' {
+                    self.handle_line()?;
+                }
+                continue;
+            }
+
+            if self.at_line_start && byte == b'{' {
+                self.write_text(&text)?;
+                text.clear();
+                self.in_json = true;
+                self.line.push(byte);
+                continue;
+            }
+
+            text.push(byte);
+            let in_escape = self.escape != Escape::None;
+            self.escape = match (self.escape, byte) {
+                (Escape::None, 0x1b) if self.at_line_start => Escape::Started,
+                (Escape::Started, b'[') => Escape::Csi,
+                // Parameter and intermediate bytes, the final byte ends the sequence
+                (Escape::Csi, 0x20..=0x3f) => Escape::Csi,
+                _ => Escape::None,
+            };
+            // Escape sequences at the start of a line, up to and including their final
+            // byte, keep it at the start
+            self.at_line_start = matches!(byte, b'\n' | b'\r')
+                || (self.at_line_start && (in_escape || self.escape != Escape::None));
+        }
+
+        self.write_text(&text)?;
+        self.out.flush()
+    }
+
+    pub fn finish(mut self) -> io::Result<Vec<Artifact>> {
+        if self.in_json {
+            self.line.push(b'\n');
+            self.handle_line()?;
+        }
+
+        let rest = self.text.finish();
+        self.out.write_all(&rest)?;
+        if let Some(json) = &mut self.json {
+            let rest = json.finish();
+            self.out.write_all(&rest)?;
+        }
+        self.out.flush()?;
+
+        Ok(self.artifacts)
+    }
+
+    /// Copy `reader` through the collector and return the collected artifacts
+    pub fn pump(mut self, mut reader: impl Read) -> io::Result<Vec<Artifact>> {
+        let mut buf = [0u8; 8192];
+        loop {
+            match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => self.feed(&buf[..n])?,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+
+        self.finish()
+    }
+
+    fn write_text(&mut self, text: &[u8]) -> io::Result<()> {
+        let rewritten = self.text.feed(text);
+        self.out.write_all(&rewritten)
+    }
+
+    fn handle_line(&mut self) -> io::Result<()> {
+        let line = std::mem::take(&mut self.line);
+        self.in_json = false;
+        self.at_line_start = true;
+
+        // A pseudo-terminal turns `\n` into `\r\n`
+        let message = line.trim_ascii_end();
+        match serde_json::from_slice::<Message>(message) {
+            Ok(parsed) => {
+                if let Message::CompilerArtifact(artifact) = parsed {
+                    self.artifacts.push(artifact);
+                }
+
+                if let Some(json) = &mut self.json {
+                    let mut rewritten = json.feed(message);
+                    rewritten.extend(json.finish());
+                    rewritten.push(b'\n');
+                    self.out.write_all(&rewritten)?;
+                }
+                Ok(())
+            }
+            // Not a cargo message after all, e.g. program output starting with `{`
+            Err(_) => self.write_text(&line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact_json(name: &str, kind: &str, test: bool, executable: bool) -> String {
+        serde_json::json!({
+            "reason": "compiler-artifact",
+            "manifest_path": "/remote/proj/Cargo.toml",
+            "target": { "name": name, "kind": [kind] },
+            "profile": { "test": test },
+            "filenames": [format!("/remote/proj/target/debug/{}", name)],
+            "executable": executable.then(|| format!("/remote/proj/target/debug/{}", name)),
+        })
+        .to_string()
+    }
+
+    fn artifact(name: &str, kind: &str, test: bool, executable: bool) -> Artifact {
+        serde_json::from_str(&artifact_json(name, kind, test, executable)).unwrap()
+    }
+
+    /// Feed `chunks` one after the other, returning the output and the artifacts
+    fn collect(chunks: &[&[u8]], echo_json: bool) -> (String, Vec<Artifact>) {
+        let rewriter = || {
+            PathRewriter::new(vec![(
+                "/remote/proj".to_string(),
+                "/home/me/proj".to_string(),
+            )])
+        };
+        let mut output = Vec::new();
+        let mut collector =
+            ArtifactCollector::new(rewriter(), echo_json.then(rewriter), &mut output);
+        for chunk in chunks {
+            collector.feed(chunk).unwrap();
+        }
+        let artifacts = collector.finish().unwrap();
+        (String::from_utf8(output).unwrap(), artifacts)
+    }
+
+    fn names(artifacts: &[Artifact]) -> Vec<&str> {
+        artifacts.iter().map(|a| a.target.name.as_str()).collect()
+    }
+
+    #[test]
+    fn collects_artifacts_and_passes_text() {
+        let input = format!(
+            "   Compiling app (/remote/proj)\n{}\n{}\n    Finished\n",
+            artifact_json("app", "bin", false, true),
+            r#"{"reason":"build-finished","success":true}"#
+        );
+        let (output, artifacts) = collect(&[input.as_bytes()], false);
+        assert_eq!(output, "   Compiling app (/home/me/proj)\n    Finished\n");
+        assert_eq!(names(&artifacts), ["app"]);
+    }
+
+    #[test]
+    fn echoes_rewritten_json() {
+        let input = format!("{}\n", artifact_json("app", "bin", false, true));
+        let (output, artifacts) = collect(&[input.as_bytes()], true);
+        assert_eq!(output, input.replace("/remote/proj", "/home/me/proj"));
+        assert_eq!(names(&artifacts), ["app"]);
+    }
+
+    #[test]
+    fn collects_across_chunks() {
+        let input = format!(
+            "warning: /remote/proj/src/main.rs\n{}\ndone\n",
+            artifact_json("app", "bin", false, true)
+        );
+        // Every possible split point, inside the text and inside the message
+        for split in 0..=input.len() {
+            let (first, second) = input.as_bytes().split_at(split);
+            let (output, artifacts) = collect(&[first, second], false);
+            assert_eq!(
+                output, "warning: /home/me/proj/src/main.rs\ndone\n",
+                "split at {}",
+                split
+            );
+            assert_eq!(names(&artifacts), ["app"], "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn collects_behind_terminal_progress_bar() {
+        // What cargo writes under a pseudo-terminal: the progress bar is cleared in
+        // front of every message and lines end in `\r\n`
+        let input = format!(
+            "\x1b[1m\x1b[32m   Compiling\x1b[0m app\r\n\
+             \x1b[1m\x1b[36m    Building\x1b[0m [====>  ] 1/2: app\r\x1b[K{}\r\n\
+             \r\x1b[K{}\r\n",
+            artifact_json("lib", "lib", false, false),
+            artifact_json("app", "bin", false, true)
+        );
+        let (output, artifacts) = collect(&[input.as_bytes()], false);
+        assert_eq!(names(&artifacts), ["lib", "app"]);
+        assert!(!output.contains("compiler-artifact"), "{:?}", output);
+        assert!(output.contains("Building\x1b[0m [====>  ] 1/2: app\r\x1b[K\r\x1b[K"));
+
+        // Split right inside the escape sequence
+        let split = input.find("\x1b[K{").unwrap() + 2;
+        let (first, second) = input.as_bytes().split_at(split);
+        let (_, artifacts) = collect(&[first, second], false);
+        assert_eq!(names(&artifacts), ["lib", "app"]);
+    }
+
+    #[test]
+    fn passes_non_json_braces() {
+        let input = "{ not json }\r\nerror: expected `{`, found `}`\n{\"reason\":1}\n";
+        let (output, artifacts) = collect(&[input.as_bytes()], false);
+        assert_eq!(output, input);
+        assert!(artifacts.is_empty());
+
+        // Only a `{` at the start of a line starts a message
+        let input = format!("text {}\n", artifact_json("app", "bin", false, true));
+        let (output, artifacts) = collect(&[input.as_bytes()], false);
+        assert_eq!(output, input.replace("/remote/proj", "/home/me/proj"));
+        assert!(artifacts.is_empty());
+    }
+
+    #[test]
+    fn finish_handles_unterminated_message() {
+        let input = artifact_json("app", "bin", false, true);
+        let (output, artifacts) = collect(&[input.as_bytes()], false);
+        assert_eq!(output, "");
+        assert_eq!(names(&artifacts), ["app"]);
+    }
+
+    #[test]
+    fn runnable_skips_tests_and_other_kinds() {
+        assert!(artifact("app", "bin", false, true).is_runnable("bin"));
+        assert!(artifact("demo", "example", false, true).is_runnable("example"));
+        // The test harness of a bin target
+        assert!(!artifact("app", "bin", true, true).is_runnable("bin"));
+        assert!(!artifact("demo", "example", false, true).is_runnable("bin"));
+        assert!(!artifact("app", "bin", false, false).is_runnable("bin"));
+    }
+
+    #[test]
+    fn copies_executables_and_native_libraries() {
+        let bin = artifact("app", "bin", false, true);
+        assert_eq!(
+            bin.files_to_copy(),
+            [Path::new("/remote/proj/target/debug/app")]
+        );
+        assert!(artifact("lib", "lib", false, false)
+            .files_to_copy()
+            .is_empty());
+        assert!(artifact("build-script-build", "custom-build", false, true)
+            .files_to_copy()
+            .is_empty());
+
+        let mut cdylib = artifact("ffi", "cdylib", false, false);
+        cdylib.filenames = vec![
+            PathBuf::from("/remote/proj/target/debug/libffi.so"),
+            PathBuf::from("/remote/proj/target/debug/libffi.rlib"),
+        ];
+        assert_eq!(
+            cdylib.files_to_copy(),
+            [Path::new("/remote/proj/target/debug/libffi.so")]
+        );
+    }
+}
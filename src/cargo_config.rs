@@ -0,0 +1,263 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+/// Keys of a project's cargo config that only make sense on the local machine: runners,
+/// credentials and settings that would move build output away from where it is collected.
+/// Linkers are kept unless they are absolute paths missing on the build host.
+pub const DEFAULT_DENY: &[&str] = &[
+    "target.*.runner",
+    "build.target-dir",
+    "registry.token",
+    "registry.credential-provider",
+    "registries.*.token",
+    "registries.*.credential-provider",
+    "paths",
+];
+
+/// Names of the cargo config file in `.cargo/`, the second one being the legacy name
+pub const FILE_NAMES: &[&str] = &["config.toml", "config"];
+
+/// The project's cargo config, `.cargo/config.toml` or the legacy `.cargo/config`
+pub fn find(project_root: &Path) -> Option<PathBuf> {
+    FILE_NAMES
+        .iter()
+        .map(|name| project_root.join(".cargo").join(name))
+        .find(|path| path.is_file())
+}
+
+/// Read the cargo config at `path` without the keys matching `deny`.
+///
+/// Patterns are dotted key paths where `*` matches any single key, e.g. `target.*.runner`.
+/// Returns the filtered config and the keys that were dropped.
+pub fn load_filtered(path: &Path, deny: &[String]) -> Result<(toml::Table, Vec<String>)> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Cannot open {}", path.display()))?;
+    let mut config: toml::Table =
+        toml::from_str(&content).with_context(|| format!("Cannot parse {}", path.display()))?;
+
+    let mut dropped = Vec::new();
+    for pattern in deny {
+        let pattern: Vec<&str> = pattern.split('.').collect();
+        remove_matching(&mut config, &pattern, "", &mut dropped);
+    }
+
+    Ok((config, dropped))
+}
+
+/// The `host.linker` and `target.<triple>.linker` tables holding a linker
+fn linker_tables(config: &mut toml::Table) -> Vec<(String, &mut toml::Table)> {
+    let mut tables = Vec::new();
+    for (key, value) in config.iter_mut() {
+        let toml::Value::Table(table) = value else {
+            continue;
+        };
+        match key.as_str() {
+            "host" => tables.push(("host".to_string(), table)),
+            "target" => {
+                for (triple, value) in table.iter_mut() {
+                    if let toml::Value::Table(target) = value {
+                        tables.push((format!("target.{}", triple), target));
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    tables
+}
+
+/// Linkers configured with an absolute path, which only work if the build host has them
+/// at the same location
+pub fn absolute_linkers(config: &toml::Table) -> Vec<String> {
+    let host = config.get("host").into_iter();
+    let targets = config
+        .get("target")
+        .and_then(|targets| targets.as_table())
+        .into_iter()
+        .flat_map(|targets| targets.values());
+
+    let mut linkers: Vec<String> = host
+        .chain(targets)
+        .filter_map(|table| table.get("linker")?.as_str())
+        .filter(|linker| linker.starts_with('/'))
+        .map(str::to_string)
+        .collect();
+    linkers.sort();
+    linkers.dedup();
+    linkers
+}
+
+/// Whether any `target.<triple>` or `target.<cfg>` table sets `rustflags`, in which case
+/// cargo ignores `build.rustflags`
+pub fn has_target_rustflags(config: &toml::Table) -> bool {
+    config
+        .get("target")
+        .and_then(|targets| targets.as_table())
+        .is_some_and(|targets| {
+            targets
+                .values()
+                .any(|target| target.get("rustflags").is_some())
+        })
+}
+
+/// The `build.target` triple, if the config sets a single one
+pub fn build_target(config: &toml::Table) -> Option<&str> {
+    config.get("build")?.get("target")?.as_str()
+}
+
+/// Whether the config sets `build.target`, a single triple or a list of them
+pub fn has_build_target(config: &toml::Table) -> bool {
+    config
+        .get("build")
+        .is_some_and(|build| build.get("target").is_some())
+}
+
+/// Remove the linkers for which `missing` is true. Returns the dropped keys and their linkers.
+pub fn remove_linkers(
+    config: &mut toml::Table,
+    missing: impl Fn(&str) -> bool,
+) -> Vec<(String, String)> {
+    let mut dropped = Vec::new();
+    for (prefix, table) in linker_tables(config) {
+        let Some(linker) = table.get("linker").and_then(|l| l.as_str()) else {
+            continue;
+        };
+        if missing(linker) {
+            let linker = linker.to_string();
+            table.remove("linker");
+            dropped.push((format!("{}.linker", prefix), linker));
+        }
+    }
+    dropped
+}
+
+fn remove_matching(
+    table: &mut toml::Table,
+    pattern: &[&str],
+    prefix: &str,
+    dropped: &mut Vec<String>,
+) {
+    let Some((first, rest)) = pattern.split_first() else {
+        return;
+    };
+
+    let keys: Vec<String> = table
+        .keys()
+        .filter(|key| *first == "*" || key == first)
+        .cloned()
+        .collect();
+    for key in keys {
+        let path = if prefix.is_empty() {
+            key.clone()
+        } else {
+            format!("{}.{}", prefix, key)
+        };
+
+        if rest.is_empty() {
+            table.remove(&key);
+            dropped.push(path);
+        } else if let Some(toml::Value::Table(nested)) = table.get_mut(&key) {
+            remove_matching(nested, rest, &path, dropped);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [host]
+        linker = "/opt/cross/bin/host-cc"
+
+        [target.x86_64-unknown-linux-gnu]
+        linker = "clang"
+        runner = "./run.sh"
+
+        [target.'cfg(target_os = "linux")']
+        linker = "/usr/bin/clang"
+
+        [target.aarch64-unknown-linux-gnu]
+        linker = "/usr/bin/clang"
+    "#;
+
+    #[test]
+    fn finds_absolute_linkers_once() {
+        let config: toml::Table = toml::from_str(CONFIG).unwrap();
+        assert_eq!(
+            absolute_linkers(&config),
+            vec!["/opt/cross/bin/host-cc", "/usr/bin/clang"]
+        );
+    }
+
+    #[test]
+    fn removes_only_missing_linkers() {
+        let mut config: toml::Table = toml::from_str(CONFIG).unwrap();
+        let mut dropped = remove_linkers(&mut config, |linker| linker == "/usr/bin/clang");
+        dropped.sort();
+        assert_eq!(
+            dropped,
+            vec![
+                (
+                    "target.aarch64-unknown-linux-gnu.linker".to_string(),
+                    "/usr/bin/clang".to_string()
+                ),
+                (
+                    "target.cfg(target_os = \"linux\").linker".to_string(),
+                    "/usr/bin/clang".to_string()
+                ),
+            ]
+        );
+
+        assert_eq!(
+            config["host"]["linker"].as_str(),
+            Some("/opt/cross/bin/host-cc")
+        );
+        let target = &config["target"]["x86_64-unknown-linux-gnu"];
+        assert_eq!(target["linker"].as_str(), Some("clang"));
+        assert_eq!(target["runner"].as_str(), Some("./run.sh"));
+        assert!(config["target"]["aarch64-unknown-linux-gnu"]
+            .get("linker")
+            .is_none());
+    }
+
+    #[test]
+    fn default_deny_keeps_linkers() {
+        let dir = std::env::temp_dir().join(format!("offload-cargo-config-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        fs::write(&path, CONFIG).unwrap();
+
+        let deny: Vec<String> = DEFAULT_DENY.iter().map(|key| key.to_string()).collect();
+        let (config, dropped) = load_filtered(&path, &deny).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(dropped, vec!["target.x86_64-unknown-linux-gnu.runner"]);
+        assert_eq!(absolute_linkers(&config).len(), 2);
+        assert_eq!(
+            config["target"]["x86_64-unknown-linux-gnu"]["linker"].as_str(),
+            Some("clang")
+        );
+    }
+
+    #[test]
+    fn reads_build_target() {
+        let config: toml::Table = toml::from_str(CONFIG).unwrap();
+        assert_eq!(build_target(&config), None);
+        assert!(!has_build_target(&config));
+
+        let config: toml::Table =
+            toml::from_str("[build]\ntarget = \"aarch64-unknown-linux-gnu\"").unwrap();
+        assert_eq!(build_target(&config), Some("aarch64-unknown-linux-gnu"));
+        assert!(has_build_target(&config));
+
+        // Several targets can't be our single default, but cargo builds them all
+        let config: toml::Table =
+            toml::from_str("build.target = [\"x86_64-unknown-linux-gnu\", \"wasm32-wasip1\"]")
+                .unwrap();
+        assert_eq!(build_target(&config), None);
+        assert!(has_build_target(&config));
+    }
+}
@@ -0,0 +1,814 @@
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::util::{local_user_name, sanitize_path_component};
+use crate::Cli;
+
+/// Name of the per-project configuration file, looked up at the workspace root
+pub const PROJECT_CONFIG_FILE: &str = ".offload.toml";
+
+/// Remote directory under which projects are synced unless a host profile overrides it
+pub const DEFAULT_REMOTE_BASE_DIR: &str = "/tmp/cargo-offload";
+
+/// Host that builds in a directory on this machine, e.g. `local:/tmp/builds`, to run the
+/// whole pipeline without a build host
+pub const LOCAL_HOST_PREFIX: &str = "local:";
+
+/// How long a shared ssh connection stays open after its last use unless configured otherwise
+pub const DEFAULT_CONTROL_PERSIST: &str = "10m";
+
+/// Settings that can be provided by `.offload.toml`, the user config, the
+/// environment or the command line.
+///
+/// Layers are applied in this order, later layers overriding earlier ones:
+/// project `.offload.toml`, `~/.config/cargo-offload/config.toml`,
+/// `CARGO_OFFLOAD_*` environment variables and finally CLI arguments.
+/// List values (`env`, `forward`, `sync.exclude`) are concatenated instead.
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    /// SSH host (user@hostname, optionally with `:port`)
+    pub host: Option<String>,
+    /// SSH port
+    pub port: Option<u16>,
+    /// Target triple
+    pub target: Option<String>,
+    /// Environment variables passed to the remote cargo command (`KEY=VALUE`)
+    pub env: Vec<String>,
+    /// Port forwards for `run-remote` (`local_port:remote_port` or `port`)
+    pub forward: Vec<String>,
+    /// Name of the entry in `hosts` to build on
+    pub profile_host: Option<String>,
+    /// Named build hosts, selected with `--profile-host <name>`
+    pub hosts: BTreeMap<String, HostProfile>,
+    /// Candidate hosts (profile names or `user@host:port`), one is picked per invocation
+    pub pool: Vec<String>,
+    /// Hosts with less free disk space (in MiB) in their remote directory are skipped
+    pub pool_min_free_mb: Option<u64>,
+    /// Add the name of the linked git worktree to the remote directory
+    pub git_worktree_remote_dir: Option<bool>,
+    /// Pass `--remap-path-prefix` so binaries refer to local instead of remote source paths
+    pub remap_path_prefix: Option<bool>,
+    /// Seconds to wait for another build of the same project to finish (0 fails immediately)
+    pub lock_timeout: Option<u64>,
+    /// `CARGO_HOME` on the build host shared by all users, so crates are fetched once per host
+    pub cargo_home: Option<String>,
+    /// SSH implementation used to reach the build host (default: openssh)
+    pub ssh_client: Option<SshClient>,
+    /// Share one ssh connection per host between all ssh and rsync calls (default: true)
+    pub ssh_multiplex: Option<bool>,
+    /// How long the shared connection stays open after the last use (ssh's `ControlPersist`)
+    pub ssh_control_persist: Option<String>,
+    pub sync: SyncConfig,
+    pub artifacts: ArtifactConfig,
+    pub container: ContainerConfig,
+    pub sccache: SccacheConfig,
+    /// Per-subcommand settings for `offload cargo <subcommand>` (`[commands.<name>]`)
+    pub commands: BTreeMap<String, CommandConfig>,
+}
+
+/// A named build host entry (`[hosts.<name>]`)
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HostProfile {
+    /// SSH host (user@hostname, optionally with `:port`)
+    pub host: String,
+    /// SSH port
+    pub port: Option<u16>,
+    /// Private key passed to ssh via `-i`
+    pub identity_file: Option<PathBuf>,
+    /// Directory on the host under which projects are synced
+    pub remote_dir: Option<String>,
+    /// Target triple used when none is configured otherwise
+    pub target: Option<String>,
+    /// Maximum number of parallel cargo jobs
+    pub jobs: Option<u32>,
+    /// sccache's cache directory on this host, instead of `sccache.dir`
+    pub sccache_dir: Option<String>,
+    /// Shared `CARGO_HOME` on this host, instead of `cargo-home`
+    pub cargo_home: Option<String>,
+}
+
+/// Connection settings of the build host used for this invocation
+#[derive(Debug, Clone)]
+pub struct HostConfig {
+    /// Profile name, if the host was selected from `hosts`
+    pub name: Option<String>,
+    /// SSH destination (user@hostname or hostname)
+    pub address: String,
+    pub port: u16,
+    pub identity_file: Option<PathBuf>,
+    pub remote_base_dir: String,
+    pub target: Option<String>,
+    pub jobs: Option<u32>,
+    pub ssh_client: SshClient,
+    /// `ControlPersist` of the shared connection, `None` if multiplexing is disabled
+    pub control_persist: Option<String>,
+    /// Commands run on this machine, with `remote_base_dir` standing in for the build host
+    pub local: bool,
+    pub sccache_dir: Option<String>,
+    /// `CARGO_HOME` shared with other users, `None` for the remote user's own
+    pub cargo_home: Option<String>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SshClient {
+    /// The `ssh` binary, configured through `~/.ssh/config`
+    #[default]
+    Openssh,
+    /// The SSH client built into offload (`native-ssh` feature), which needs neither
+    /// `ssh` nor `rsync` locally
+    Native,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SyncConfig {
+    /// How the sources get to the remote (default: rsync)
+    pub method: Option<SyncMethod>,
+    /// Additional exclude patterns
+    pub exclude: Vec<String>,
+    /// Leave out files ignored by git (default: true)
+    pub gitignore: Option<bool>,
+    /// Sync the project's `.cargo/config.toml` (default: true)
+    pub cargo_config: Option<bool>,
+    /// Keys of the project's cargo config that are not synced, in addition to the defaults
+    pub cargo_config_deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SyncMethod {
+    /// Mirror the project directory with rsync
+    Rsync,
+    /// Keep a remote git checkout at the local `HEAD` and ship the local changes on top
+    Git,
+    /// Send only files whose content the remote lacks, using nothing but `ssh` and `tar`
+    Builtin,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ArtifactConfig {
+    /// Copy the whole profile directory including `deps/`, `build/` and `incremental/`
+    pub copy_all: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ContainerConfig {
+    /// Image cargo runs in on the build host, e.g. `rust:1.80-bookworm`
+    pub image: Option<String>,
+    /// Container engine installed on the build host (default: docker)
+    pub engine: Option<ContainerEngine>,
+    /// Additional options for creating the container, e.g. `["--cpus", "8"]`
+    pub options: Vec<String>,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SccacheConfig {
+    /// Compile through sccache on the build host, installing it there if needed (default: false)
+    pub enabled: Option<bool>,
+    /// Cache directory on the build host shared by all projects and users (default:
+    /// `sccache` in the remote base directory)
+    pub dir: Option<String>,
+    /// Maximum size of the cache, e.g. `20G` (default: sccache's own)
+    pub cache_size: Option<String>,
+    /// Show the cache hits and misses of each build (default: false)
+    pub stats: Option<bool>,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ContainerEngine {
+    #[default]
+    Docker,
+    Podman,
+}
+
+#[derive(Debug, Default, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CommandConfig {
+    /// Paths relative to `target/<triple>/` copied back after the command succeeded
+    pub artifacts: Vec<String>,
+}
+
+impl Config {
+    /// Load the layered configuration for the project located at `project_root`
+    pub fn load(project_root: &Path, cli: &Cli) -> Result<Self> {
+        let mut config = Config::default();
+
+        let project_file = project_root.join(PROJECT_CONFIG_FILE);
+        if let Some(project) = Self::from_file(&project_file)? {
+            config.merge(project);
+        }
+
+        if let Some(user_file) = user_config_path() {
+            if let Some(user) = Self::from_file(&user_file)? {
+                config.merge(user);
+            }
+        }
+
+        config.merge(Self::from_env()?);
+        config.merge(Self::from_cli(cli));
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content =
+            fs::read_to_string(path).with_context(|| format!("Cannot open {}", path.display()))?;
+        let config = Self::from_toml(&content)
+            .with_context(|| format!("Cannot parse {}", path.display()))?;
+
+        debug!("Loaded configuration from {}", path.display());
+        Ok(Some(config))
+    }
+
+    /// One configuration layer in the format of `.offload.toml`
+    fn from_toml(content: &str) -> Result<Self> {
+        let mut config: Config = toml::from_str(content)?;
+        config.split_host_port();
+        Ok(config)
+    }
+
+    fn from_env() -> Result<Self> {
+        let mut config = Config {
+            host: std::env::var("CARGO_OFFLOAD_HOST").ok(),
+            target: std::env::var("CARGO_OFFLOAD_TARGET").ok(),
+            profile_host: std::env::var("CARGO_OFFLOAD_PROFILE_HOST").ok(),
+            ..Default::default()
+        };
+
+        if let Ok(port) = std::env::var("CARGO_OFFLOAD_PORT") {
+            config.port = Some(
+                port.parse()
+                    .with_context(|| format!("Invalid CARGO_OFFLOAD_PORT `{port}`"))?,
+            );
+        }
+
+        config.split_host_port();
+        Ok(config)
+    }
+
+    fn from_cli(cli: &Cli) -> Self {
+        let mut config = Config {
+            host: cli.host.clone(),
+            port: cli.port,
+            target: cli.target.clone(),
+            env: cli.env_vars.clone(),
+            forward: cli.forward_ports.clone(),
+            profile_host: cli.profile_host.clone(),
+            ..Default::default()
+        };
+
+        // The flag can only enable copying, so leave lower layers alone when it is absent
+        if cli.copy_all_artifacts {
+            config.artifacts.copy_all = Some(true);
+        }
+
+        config.split_host_port();
+        config
+    }
+
+    /// Split a `host:port` value into its parts. An explicit `port` in the same
+    /// layer takes precedence over the one embedded in the host string.
+    fn split_host_port(&mut self) {
+        let Some(host_str) = self.host.take() else {
+            return;
+        };
+
+        if host_str.starts_with(LOCAL_HOST_PREFIX) {
+            self.host = Some(host_str);
+            return;
+        }
+
+        // Parse format: user@host:port or host:port or just host
+        if let Some(colon_pos) = host_str.rfind(':') {
+            let (host_part, port_part) = host_str.split_at(colon_pos);
+            if let Ok(port) = port_part[1..].parse::<u16>() {
+                self.host = Some(host_part.to_string());
+                self.port = self.port.or(Some(port));
+                return;
+            }
+        }
+
+        self.host = Some(host_str);
+    }
+
+    fn merge(&mut self, other: Config) {
+        // Whichever layer selects the build host last wins, be it a single host or a pool
+        if other.host.is_some() || other.profile_host.is_some() {
+            self.pool.clear();
+            if other.profile_host.is_none() {
+                self.profile_host = None;
+            }
+        } else if !other.pool.is_empty() {
+            self.host = None;
+            self.profile_host = None;
+        }
+
+        // A port set next to an earlier host doesn't carry over to a different host
+        if other.host.is_some() {
+            self.host = other.host;
+            self.port = other.port;
+        } else if other.port.is_some() {
+            self.port = other.port;
+        }
+        if other.target.is_some() {
+            self.target = other.target;
+        }
+        self.env.extend(other.env);
+        self.forward.extend(other.forward);
+        if other.profile_host.is_some() {
+            self.profile_host = other.profile_host;
+        }
+        self.hosts.extend(other.hosts);
+        if !other.pool.is_empty() {
+            self.pool = other.pool;
+        }
+        if other.pool_min_free_mb.is_some() {
+            self.pool_min_free_mb = other.pool_min_free_mb;
+        }
+        if other.git_worktree_remote_dir.is_some() {
+            self.git_worktree_remote_dir = other.git_worktree_remote_dir;
+        }
+        if other.remap_path_prefix.is_some() {
+            self.remap_path_prefix = other.remap_path_prefix;
+        }
+        if other.lock_timeout.is_some() {
+            self.lock_timeout = other.lock_timeout;
+        }
+        if other.cargo_home.is_some() {
+            self.cargo_home = other.cargo_home;
+        }
+        if other.ssh_client.is_some() {
+            self.ssh_client = other.ssh_client;
+        }
+        if other.ssh_multiplex.is_some() {
+            self.ssh_multiplex = other.ssh_multiplex;
+        }
+        if other.ssh_control_persist.is_some() {
+            self.ssh_control_persist = other.ssh_control_persist;
+        }
+        self.sync.exclude.extend(other.sync.exclude);
+        if other.sync.method.is_some() {
+            self.sync.method = other.sync.method;
+        }
+        if other.sync.gitignore.is_some() {
+            self.sync.gitignore = other.sync.gitignore;
+        }
+        if other.sync.cargo_config.is_some() {
+            self.sync.cargo_config = other.sync.cargo_config;
+        }
+        self.sync
+            .cargo_config_deny
+            .extend(other.sync.cargo_config_deny);
+        if other.artifacts.copy_all.is_some() {
+            self.artifacts.copy_all = other.artifacts.copy_all;
+        }
+        if other.container.image.is_some() {
+            self.container.image = other.container.image;
+        }
+        if other.container.engine.is_some() {
+            self.container.engine = other.container.engine;
+        }
+        self.container.options.extend(other.container.options);
+        if other.sccache.enabled.is_some() {
+            self.sccache.enabled = other.sccache.enabled;
+        }
+        if other.sccache.dir.is_some() {
+            self.sccache.dir = other.sccache.dir;
+        }
+        if other.sccache.cache_size.is_some() {
+            self.sccache.cache_size = other.sccache.cache_size;
+        }
+        if other.sccache.stats.is_some() {
+            self.sccache.stats = other.sccache.stats;
+        }
+        self.commands.extend(other.commands);
+    }
+
+    /// Resolve the build host, either from the selected profile or from `host`/`port`
+    pub fn selected_host(&self) -> Result<HostConfig> {
+        if let Some(name) = &self.profile_host {
+            return self.profile(name);
+        }
+
+        let host = self.host.clone().context(
+            "Host must be specified via --host, --profile-host, CARGO_OFFLOAD_HOST env var or .offload.toml",
+        )?;
+        if let Some(dir) = host.strip_prefix(LOCAL_HOST_PREFIX) {
+            return self.local_host(dir);
+        }
+
+        Ok(HostConfig {
+            name: None,
+            address: host,
+            port: self.port.unwrap_or(22),
+            identity_file: None,
+            remote_base_dir: DEFAULT_REMOTE_BASE_DIR.to_string(),
+            target: None,
+            jobs: None,
+            ssh_client: self.ssh_client.unwrap_or_default(),
+            control_persist: self.control_persist(),
+            local: false,
+            sccache_dir: None,
+            cargo_home: self.cargo_home.clone(),
+        })
+    }
+
+    /// Resolve every entry of `pool`, either a profile name or a plain `user@host:port`
+    pub fn pool_hosts(&self) -> Result<Vec<HostConfig>> {
+        self.pool
+            .iter()
+            .map(|entry| {
+                if self.hosts.contains_key(entry) {
+                    return self.profile(entry);
+                }
+                if let Some(dir) = entry.strip_prefix(LOCAL_HOST_PREFIX) {
+                    return self.local_host(dir);
+                }
+
+                let mut host = Config {
+                    host: Some(entry.clone()),
+                    ..Default::default()
+                };
+                host.split_host_port();
+
+                Ok(HostConfig {
+                    name: None,
+                    address: host.host.unwrap_or_default(),
+                    port: host.port.unwrap_or(22),
+                    identity_file: None,
+                    remote_base_dir: DEFAULT_REMOTE_BASE_DIR.to_string(),
+                    target: None,
+                    jobs: None,
+                    ssh_client: self.ssh_client.unwrap_or_default(),
+                    control_persist: self.control_persist(),
+                    local: false,
+                    sccache_dir: None,
+                    cargo_home: self.cargo_home.clone(),
+                })
+            })
+            .collect()
+    }
+
+    fn profile(&self, name: &str) -> Result<HostConfig> {
+        let Some(profile) = self.hosts.get(name) else {
+            let available = self.hosts.keys().cloned().collect::<Vec<_>>();
+            if available.is_empty() {
+                bail!("Unknown host profile `{name}`: no hosts are configured");
+            }
+            bail!(
+                "Unknown host profile `{name}` (available: {})",
+                available.join(", ")
+            );
+        };
+
+        let host = profile.resolve(name);
+        Ok(HostConfig {
+            ssh_client: self.ssh_client.unwrap_or_default(),
+            control_persist: self.control_persist(),
+            cargo_home: host.cargo_home.clone().or_else(|| self.cargo_home.clone()),
+            ..host
+        })
+    }
+
+    /// A `local:<dir>` host: builds run on this machine below `<dir>` (default: the usual
+    /// remote directory)
+    fn local_host(&self, dir: &str) -> Result<HostConfig> {
+        let remote_base_dir = if dir.is_empty() {
+            DEFAULT_REMOTE_BASE_DIR.to_string()
+        } else {
+            std::path::absolute(expand_tilde(Path::new(dir)))
+                .with_context(|| format!("Invalid local build directory `{}`", dir))?
+                .to_string_lossy()
+                .to_string()
+        };
+
+        Ok(HostConfig {
+            name: None,
+            address: format!("{}{}", LOCAL_HOST_PREFIX, remote_base_dir),
+            port: 0,
+            identity_file: None,
+            remote_base_dir,
+            target: None,
+            jobs: None,
+            ssh_client: SshClient::default(),
+            control_persist: None,
+            local: true,
+            sccache_dir: None,
+            cargo_home: self.cargo_home.clone(),
+        })
+    }
+
+    /// Whether ssh connections are shared, which OpenSSH only supports on Unix. The
+    /// native client keeps a single connection per invocation anyway.
+    pub fn ssh_multiplex(&self) -> bool {
+        cfg!(unix)
+            && self.ssh_client.unwrap_or_default() == SshClient::Openssh
+            && self.ssh_multiplex.unwrap_or(true)
+    }
+
+    /// Prepare the directory of the shared connections' sockets, turning sharing off with
+    /// a warning if it cannot be used safely
+    pub fn prepare_ssh_multiplex(&mut self) {
+        if !self.ssh_multiplex() {
+            return;
+        }
+        if let Err(e) = create_control_dir() {
+            warn!("Not sharing ssh connections: {:#}", e);
+            self.ssh_multiplex = Some(false);
+        }
+    }
+
+    fn control_persist(&self) -> Option<String> {
+        self.ssh_multiplex().then(|| {
+            self.ssh_control_persist
+                .clone()
+                .unwrap_or_else(|| DEFAULT_CONTROL_PERSIST.to_string())
+        })
+    }
+}
+
+impl HostProfile {
+    pub fn resolve(&self, name: &str) -> HostConfig {
+        let mut host = Config {
+            host: Some(self.host.clone()),
+            port: self.port,
+            ..Default::default()
+        };
+        host.split_host_port();
+
+        HostConfig {
+            name: Some(name.to_string()),
+            address: host.host.unwrap_or_default(),
+            port: host.port.unwrap_or(22),
+            identity_file: self.identity_file.as_deref().map(expand_tilde),
+            remote_base_dir: self
+                .remote_dir
+                .clone()
+                .unwrap_or_else(|| DEFAULT_REMOTE_BASE_DIR.to_string()),
+            target: self.target.clone(),
+            jobs: self.jobs,
+            ssh_client: SshClient::default(),
+            control_persist: None,
+            local: false,
+            sccache_dir: self.sccache_dir.clone(),
+            cargo_home: self.cargo_home.clone(),
+        }
+    }
+}
+
+impl HostConfig {
+    /// Options passed to every ssh invocation, including the ones made by rsync
+    pub fn ssh_options(&self) -> Vec<String> {
+        let mut options = vec!["-p".to_string(), self.port.to_string()];
+        if let Some(identity_file) = &self.identity_file {
+            options.push("-i".to_string());
+            options.push(identity_file.to_string_lossy().to_string());
+        }
+        if let Some(persist) = &self.control_persist {
+            // The first connection to the host becomes the master, later ones reuse it
+            let control_path = control_dir().join("%C");
+            options.extend([
+                "-o".to_string(),
+                "ControlMaster=auto".to_string(),
+                "-o".to_string(),
+                format!("ControlPath={}", control_path.display()),
+                "-o".to_string(),
+                format!("ControlPersist={}", persist),
+            ]);
+        }
+        options
+    }
+
+    /// Human readable name used in log messages
+    pub fn display_name(&self) -> String {
+        if self.local {
+            return self.address.clone();
+        }
+        match &self.name {
+            Some(name) => format!("{} ({}:{})", name, self.address, self.port),
+            None => format!("{}:{}", self.address, self.port),
+        }
+    }
+}
+
+/// Expand a leading `~/` to the home directory
+pub fn expand_tilde(path: &Path) -> PathBuf {
+    if let Ok(rest) = path.strip_prefix("~") {
+        if let Some(home) = std::env::var_os("HOME") {
+            return PathBuf::from(home).join(rest);
+        }
+    }
+
+    path.to_path_buf()
+}
+
+/// Directory of the shared connections' sockets. Socket paths are limited to ~100 bytes,
+/// so this stays short rather than using `std::env::temp_dir()`.
+pub fn control_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").filter(|dir| !dir.is_empty()) {
+        Some(runtime_dir) => PathBuf::from(runtime_dir).join("cargo-offload"),
+        None => PathBuf::from(format!(
+            "/tmp/cargo-offload-{}",
+            sanitize_path_component(&local_user_name())
+        )),
+    }
+}
+
+/// Create `control_dir()`, accessible only by the current user.
+///
+/// The directory may already exist, e.g. created by someone else in the shared `/tmp`, so
+/// it is only accepted if it is a real directory owned by us that nobody else can access.
+fn create_control_dir() -> Result<()> {
+    let dir = control_dir();
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder
+        .create(&dir)
+        .with_context(|| format!("Cannot create {}", dir.display()))?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        let metadata = fs::symlink_metadata(&dir)
+            .with_context(|| format!("Cannot access {}", dir.display()))?;
+        if !metadata.file_type().is_dir() {
+            bail!("{} is not a directory", dir.display());
+        }
+        if metadata.uid() != current_uid()? {
+            bail!("{} is owned by another user", dir.display());
+        }
+        let mode = metadata.permissions().mode() & 0o777;
+        if mode != 0o700 {
+            bail!("{} has mode {:o} instead of 700", dir.display(), mode);
+        }
+    }
+
+    Ok(())
+}
+
+/// User id of this process, as reported by `id -u`
+#[cfg(unix)]
+fn current_uid() -> Result<u32> {
+    let output = std::process::Command::new("id")
+        .arg("-u")
+        .output()
+        .context("Executing `id -u` failed")?;
+    if !output.status.success() {
+        bail!("`id -u` failed");
+    }
+
+    let uid = String::from_utf8_lossy(&output.stdout);
+    uid.trim()
+        .parse()
+        .with_context(|| format!("Invalid `id -u` output `{}`", uid.trim()))
+}
+
+/// Location of the user configuration file (`$XDG_CONFIG_HOME` or `~/.config`)
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+
+    Some(config_dir.join("cargo-offload").join("config.toml"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Merge `layers` in order, the way `load()` merges the project, user, env and CLI layers
+    fn merged(layers: &[&str]) -> Config {
+        let mut config = Config::default();
+        for layer in layers {
+            config.merge(Config::from_toml(layer).unwrap());
+        }
+        config
+    }
+
+    #[test]
+    fn later_layers_override_earlier_ones() {
+        let project = r#"
+            host = "project-host"
+            target = "aarch64-unknown-linux-gnu"
+            env = ["A=1"]
+            lock-timeout = 10
+            [sync]
+            exclude = ["*.log"]
+            gitignore = false
+        "#;
+        let user = r#"
+            target = "x86_64-unknown-linux-gnu"
+            env = ["B=2"]
+            [sync]
+            exclude = ["data/"]
+        "#;
+        let env = r#"host = "env-host""#;
+        let cli = r#"
+            env = ["C=3"]
+            forward = ["8080"]
+        "#;
+
+        let config = merged(&[project, user, env, cli]);
+        assert_eq!(config.host.as_deref(), Some("env-host"));
+        assert_eq!(config.target.as_deref(), Some("x86_64-unknown-linux-gnu"));
+        assert_eq!(config.lock_timeout, Some(10));
+        assert_eq!(config.sync.gitignore, Some(false));
+        // Lists are concatenated
+        assert_eq!(config.env, vec!["A=1", "B=2", "C=3"]);
+        assert_eq!(config.forward, vec!["8080"]);
+        assert_eq!(config.sync.exclude, vec!["*.log", "data/"]);
+    }
+
+    #[test]
+    fn later_host_resets_the_port() {
+        let config = merged(&[r#"host = "first:2222""#, r#"host = "second""#]);
+        assert_eq!(config.host.as_deref(), Some("second"));
+        assert_eq!(config.port, None);
+        assert_eq!(config.selected_host().unwrap().port, 22);
+
+        let config = merged(&["host = \"first\"\nport = 2222", r#"host = "second:23""#]);
+        assert_eq!(config.host.as_deref(), Some("second"));
+        assert_eq!(config.port, Some(23));
+    }
+
+    #[test]
+    fn later_port_applies_to_earlier_host() {
+        let config = merged(&[r#"host = "first:2222""#, "port = 2200"]);
+        assert_eq!(config.host.as_deref(), Some("first"));
+        assert_eq!(config.port, Some(2200));
+    }
+
+    #[test]
+    fn splits_host_and_port() {
+        let layer = |content: &str| {
+            let config = Config::from_toml(content).unwrap();
+            (config.host.unwrap(), config.port)
+        };
+
+        assert_eq!(
+            layer(r#"host = "user@build:2222""#),
+            ("user@build".to_string(), Some(2222))
+        );
+        assert_eq!(layer(r#"host = "build""#), ("build".to_string(), None));
+        // An explicit port in the same layer wins
+        assert_eq!(
+            layer("host = \"build:2222\"\nport = 23"),
+            ("build".to_string(), Some(23))
+        );
+        // Not a port, left as it is
+        assert_eq!(
+            layer(r#"host = "build:ssh""#),
+            ("build:ssh".to_string(), None)
+        );
+        assert_eq!(
+            layer(r#"host = "local:/tmp/builds:1""#),
+            ("local:/tmp/builds:1".to_string(), None)
+        );
+    }
+
+    #[test]
+    fn later_host_or_pool_replaces_the_other() {
+        let config = merged(&[r#"pool = ["a", "b"]"#, r#"host = "single""#]);
+        assert!(config.pool.is_empty());
+        assert_eq!(config.host.as_deref(), Some("single"));
+
+        let config = merged(&[
+            r#"profile-host = "named""#,
+            r#"host = "single""#,
+            r#"pool = ["a", "b"]"#,
+        ]);
+        assert_eq!(config.pool, vec!["a", "b"]);
+        assert_eq!(config.host, None);
+        assert_eq!(config.profile_host, None);
+    }
+
+    #[test]
+    fn rejects_unknown_keys() {
+        assert!(Config::from_toml("hots = \"typo\"").is_err());
+    }
+}
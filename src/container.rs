@@ -0,0 +1,283 @@
+use crate::config::{ContainerConfig, ContainerEngine};
+use crate::util::{sanitize_path_component, shell_join, shell_quote, stable_hash};
+
+/// Label recording the settings a container was created with, to replace outdated ones
+const CONFIG_LABEL: &str = "cargo-offload.config";
+
+/// Volumes shared by all projects on a host, so crates are downloaded once
+const REGISTRY_VOLUME: &str = "cargo-offload-registry";
+const GIT_VOLUME: &str = "cargo-offload-git";
+
+/// Long-lived container on the build host that cargo runs in, one per project
+#[derive(Debug, Clone)]
+pub struct Container {
+    engine: ContainerEngine,
+    image: String,
+    options: Vec<String>,
+    name: String,
+    /// Remote directory mounted at the same path, so paths in cargo's output are unchanged
+    mount: String,
+    /// Further directories on the host that are mounted at the same path
+    shared_dirs: Vec<String>,
+}
+
+impl Container {
+    /// The container for the project synced to `remote_root`, `None` if no image is configured
+    pub fn new(
+        config: ContainerConfig,
+        remote_root: &str,
+        shared_dirs: Vec<String>,
+    ) -> Option<Container> {
+        let image = config.image?;
+
+        // `<user>-<project>` from `<base>/<user>/<project>`
+        let mut components = remote_root.rsplit('/').filter(|c| !c.is_empty());
+        let project = components.next().unwrap_or("root");
+        let name = match components.next() {
+            Some(user) => format!("cargo-offload-{}-{}", user, project),
+            None => format!("cargo-offload-{}", project),
+        };
+
+        Some(Container {
+            engine: config.engine.unwrap_or_default(),
+            image,
+            options: config.options,
+            name: sanitize_path_component(&name),
+            mount: remote_root.to_string(),
+            shared_dirs,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn image(&self) -> &str {
+        &self.image
+    }
+
+    fn engine(&self) -> &'static str {
+        match self.engine {
+            ContainerEngine::Docker => "docker",
+            ContainerEngine::Podman => "podman",
+        }
+    }
+
+    /// Identifies the settings the container is created with
+    fn config_key(&self) -> String {
+        let key = format!(
+            "{}\0{}\0{}\0{}",
+            self.image,
+            self.mount,
+            self.shared_dirs.join("\0"),
+            self.options.join("\0")
+        );
+        format!("{:016x}", stable_hash(key.as_bytes()))
+    }
+
+    /// Shell command creating the container, unless it is running with the current settings.
+    ///
+    /// The registry volumes are mounted into the image's `CARGO_HOME`, and the host network
+    /// is used so forwarded ports reach programs started by `run-remote`.
+    pub fn start_command(&self) -> String {
+        let engine = self.engine();
+        let name = shell_quote(&self.name);
+        let image = shell_quote(&self.image);
+        let key = self.config_key();
+        let mount = shell_quote(&self.mount);
+        let mut dirs = vec![mount.clone()];
+        let mut volumes = format!("-v {}:{}", mount, mount);
+        for dir in &self.shared_dirs {
+            let dir = shell_quote(dir);
+            volumes.push_str(&format!(" -v {}:{}", dir, dir));
+            dirs.push(dir);
+        }
+
+        format!(
+            "if [ \"$({engine} inspect -f {inspect_format} {name} 2>/dev/null)\" != {expected} ]; then \
+             {engine} rm -f {name} >/dev/null 2>&1; \
+             mkdir -p {dirs} && \
+             cargo_home=$({engine} run --rm --entrypoint sh {image} -c 'echo \"${{CARGO_HOME:-$HOME/.cargo}}\"') && \
+             {engine} run -d --name {name} --label {label} --init --network host \
+             {volumes} -v {registry}:\"$cargo_home/registry\" -v {git}:\"$cargo_home/git\" \
+             {options}--entrypoint tail {image} -f /dev/null >/dev/null; \
+             fi",
+            inspect_format = shell_quote(&format!(
+                "{{{{index .Config.Labels \"{}\"}}}} {{{{.State.Running}}}}",
+                CONFIG_LABEL
+            )),
+            expected = shell_quote(&format!("{} true", key)),
+            label = shell_quote(&format!("{}={}", CONFIG_LABEL, key)),
+            dirs = dirs.join(" "),
+            registry = REGISTRY_VOLUME,
+            git = GIT_VOLUME,
+            options = if self.options.is_empty() {
+                String::new()
+            } else {
+                format!("{} ", shell_join(&self.options))
+            },
+        )
+    }
+
+    /// Shell condition that holds while the container is running
+    pub fn running_command(&self) -> String {
+        format!(
+            "[ \"$({} inspect -f '{{{{.State.Running}}}}' {} 2>/dev/null)\" = true ]",
+            self.engine(),
+            shell_quote(&self.name)
+        )
+    }
+
+    /// Shell command running `command` in the container instead of on the host. A terminal
+    /// is only requested if the remote command has one.
+    pub fn exec_command(&self, command: &str) -> String {
+        format!(
+            "{} exec -i $([ -t 0 ] && echo -t) {} sh -c {}",
+            self.engine(),
+            shell_quote(&self.name),
+            shell_quote(command)
+        )
+    }
+
+    /// Shell command removing the container, after deleting the files it created in the
+    /// mounted directory, which may belong to a user that only exists inside it
+    pub fn remove_command(&self) -> String {
+        let engine = self.engine();
+        let name = shell_quote(&self.name);
+        format!(
+            "{engine} exec {name} find {mount} -mindepth 1 -delete >/dev/null 2>&1; \
+             {engine} rm -f {name} >/dev/null 2>&1; true",
+            mount = shell_quote(&self.mount),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::temp_dir;
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    fn config(image: &str, options: &[&str]) -> ContainerConfig {
+        ContainerConfig {
+            image: Some(image.to_string()),
+            engine: None,
+            options: options.iter().map(|o| o.to_string()).collect(),
+        }
+    }
+
+    fn name(remote_root: &str) -> String {
+        Container::new(config("rust", &[]), remote_root, Vec::new())
+            .unwrap()
+            .name
+    }
+
+    #[test]
+    fn only_created_with_an_image() {
+        assert!(Container::new(ContainerConfig::default(), "/srv/alice/app", Vec::new()).is_none());
+    }
+
+    #[test]
+    fn named_after_user_and_project() {
+        assert_eq!(name("/srv/offload/alice/app"), "cargo-offload-alice-app");
+        assert_eq!(name("/srv/offload/alice/app/"), "cargo-offload-alice-app");
+        assert_eq!(name("/app"), "cargo-offload-app");
+        assert_eq!(name("/"), "cargo-offload-root");
+        assert_eq!(
+            name("/srv/bob smith/my app:v2"),
+            "cargo-offload-bob_smith-my_app_v2"
+        );
+    }
+
+    #[test]
+    fn config_key_changes_with_the_settings() {
+        let key = |config: ContainerConfig, remote_root: &str, shared_dirs: &[&str]| {
+            let shared_dirs = shared_dirs.iter().map(|d| d.to_string()).collect();
+            Container::new(config, remote_root, shared_dirs)
+                .unwrap()
+                .config_key()
+        };
+        let base = key(
+            config("rust:1.80", &["--cpus", "8"]),
+            "/srv/a/app",
+            &["/cache"],
+        );
+        assert_eq!(base.len(), 16);
+        assert_eq!(
+            base,
+            key(
+                config("rust:1.80", &["--cpus", "8"]),
+                "/srv/a/app",
+                &["/cache"]
+            )
+        );
+
+        let changed = [
+            key(
+                config("rust:1.81", &["--cpus", "8"]),
+                "/srv/a/app",
+                &["/cache"],
+            ),
+            key(
+                config("rust:1.80", &["--cpus", "4"]),
+                "/srv/a/app",
+                &["/cache"],
+            ),
+            key(
+                config("rust:1.80", &["--cpus", "8"]),
+                "/srv/a/other",
+                &["/cache"],
+            ),
+            key(config("rust:1.80", &["--cpus", "8"]), "/srv/a/app", &[]),
+            // The separators keep shifted values apart
+            key(
+                config("rust:1.80", &["--cpus"]),
+                "/srv/a/app",
+                &["/cache", "8"],
+            ),
+        ];
+        for key in changed {
+            assert_ne!(key, base);
+        }
+    }
+
+    #[test]
+    fn start_command_quotes_mounts_and_options() {
+        let dir = temp_dir("container-start");
+        let mount = dir.join("it's a project").to_string_lossy().into_owned();
+        let shared = dir.join("shared $HOME").to_string_lossy().into_owned();
+        let container = Container::new(
+            config("rust:1.80", &["--cpus", "8", "--env=GREETING=hello world"]),
+            &mount,
+            vec![shared.clone()],
+        )
+        .unwrap();
+
+        // Record each docker invocation as one line with every argument in brackets
+        let log = dir.join("docker.log");
+        let script = format!(
+            "docker() {{ {{ printf '<%s>' \"$@\"; echo; }} >> {}; }}; {}",
+            shell_quote(&log.to_string_lossy()),
+            container.start_command()
+        );
+        let status = Command::new("sh").arg("-c").arg(script).status().unwrap();
+        let invocations = fs::read_to_string(&log).unwrap();
+        let created = (Path::new(&mount).is_dir(), Path::new(&shared).is_dir());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert!(status.success());
+        assert_eq!(created, (true, true));
+        let run = invocations.lines().last().unwrap();
+        let name = container.name();
+        for expected in [
+            format!("<run><-d><--name><{}>", name),
+            format!("<--label><{}={}>", CONFIG_LABEL, container.config_key()),
+            format!("<-v><{0}:{0}><-v><{1}:{1}>", mount, shared),
+            "<--cpus><8><--env=GREETING=hello world><--entrypoint><tail><rust:1.80>".to_string(),
+        ] {
+            assert!(run.contains(&expected), "{} not in {}", expected, run);
+        }
+    }
+}
@@ -0,0 +1,492 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io;
+use std::path::Path;
+use std::time::UNIX_EPOCH;
+
+use anyhow::{Context, Result};
+use ignore::overrides::OverrideBuilder;
+use log::{debug, warn};
+use sha2::{Digest, Sha256};
+
+use crate::sync_filter;
+
+/// State of a synced file, as recorded in the manifest kept next to the remote directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    pub size: u64,
+    /// Modification time in nanoseconds since the epoch
+    pub mtime: u128,
+    /// Hex encoded SHA-256 of the content, or `link:<target>` for a symbolic link
+    pub hash: String,
+}
+
+/// Prefix of the `hash` of symbolic links, followed by the link target
+const LINK_PREFIX: &str = "link:";
+
+/// Synced files by their path relative to the mirrored root
+pub type Manifest = BTreeMap<String, Entry>;
+
+/// Parse a manifest of `<hash>\t<size>\t<mtime>\t<path>` lines, skipping malformed ones
+pub fn parse_manifest(content: &str) -> Manifest {
+    content
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.splitn(4, '\t');
+            let hash = fields.next()?.to_string();
+            let size = fields.next()?.parse().ok()?;
+            let mtime = fields.next()?.parse().ok()?;
+            let path = fields.next()?.to_string();
+            Some((path, Entry { size, mtime, hash }))
+        })
+        .collect()
+}
+
+pub fn format_manifest(manifest: &Manifest) -> String {
+    let mut content = String::new();
+    for (path, entry) in manifest {
+        content.push_str(&format!(
+            "{}\t{}\t{}\t{}\n",
+            entry.hash, entry.size, entry.mtime, path
+        ));
+    }
+    content
+}
+
+/// Build the manifest of the files to sync in `dirs`, with paths relative to `root`.
+///
+/// Files whose size and modification time match `previous` keep their recorded hash,
+/// only new and modified files are read. Symbolic links are recorded with their target,
+/// like rsync `-a` they are synced as links rather than followed.
+pub fn scan(
+    root: &Path,
+    dirs: &[&Path],
+    gitignore: bool,
+    excludes: &[String],
+    previous: &Manifest,
+) -> Result<Manifest> {
+    let mut manifest = Manifest::new();
+    let mut hashed = 0;
+
+    for dir in dirs {
+        let mut overrides = OverrideBuilder::new(dir);
+        for pattern in excludes {
+            overrides
+                .add(&format!("!{}", pattern))
+                .with_context(|| format!("Invalid exclude pattern `{}`", pattern))?;
+        }
+
+        let walker = sync_filter::walker(dir, gitignore)
+            .overrides(overrides.build()?)
+            .build();
+        for entry in walker {
+            let entry = entry.with_context(|| format!("Cannot walk {}", dir.display()))?;
+            let Some(file_type) = entry.file_type() else {
+                continue;
+            };
+            if !file_type.is_file() && !file_type.is_symlink() {
+                continue;
+            }
+
+            let relative = entry
+                .path()
+                .strip_prefix(root)?
+                .to_string_lossy()
+                .to_string();
+            if relative.contains(['\n', '\t']) {
+                warn!("Skipping {}: unsupported file name", entry.path().display());
+                continue;
+            }
+
+            let metadata = entry.metadata()?;
+            let mtime = metadata
+                .modified()?
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos();
+
+            if file_type.is_symlink() {
+                let target = fs::read_link(entry.path())
+                    .with_context(|| format!("Cannot read link {}", entry.path().display()))?;
+                let target = target.to_string_lossy();
+                if target.contains(['\n', '\t']) {
+                    warn!(
+                        "Skipping {}: unsupported link target",
+                        entry.path().display()
+                    );
+                    continue;
+                }
+                // Size 0, so links are always sent instead of being copied on the remote
+                let hash = format!("{}{}", LINK_PREFIX, target);
+                manifest.insert(
+                    relative,
+                    Entry {
+                        size: 0,
+                        mtime,
+                        hash,
+                    },
+                );
+                continue;
+            }
+
+            let size = metadata.len();
+            let hash = match previous.get(&relative) {
+                Some(known) if known.size == size && known.mtime == mtime => known.hash.clone(),
+                _ => {
+                    hashed += 1;
+                    hash_file(entry.path())?
+                }
+            };
+            manifest.insert(relative, Entry { size, mtime, hash });
+        }
+    }
+
+    debug!("Scanned {} files, hashed {}", manifest.len(), hashed);
+    Ok(manifest)
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = File::open(path).with_context(|| format!("Cannot open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Cannot read {}", path.display()))?;
+
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect())
+}
+
+/// What has to happen on the remote to turn `remote` into `local`, in this order
+#[derive(Debug, Default)]
+pub struct Plan {
+    /// Files and directories in the way of a local path, a file that became a directory
+    /// or the other way round. They are removed before anything else.
+    pub replaced: Vec<String>,
+    /// Files whose content already exists remotely under another path, `(from, to)`
+    pub copies: Vec<(String, String)>,
+    /// Files and directories to remove, including directories left without files
+    pub deletes: Vec<String>,
+    /// Files to send
+    pub uploads: Vec<String>,
+}
+
+impl Plan {
+    pub fn new(local: &Manifest, remote: &Manifest) -> Plan {
+        let local_dirs = parent_dirs(local);
+        let remote_dirs = parent_dirs(remote);
+
+        let mut plan = Plan::default();
+        for path in remote.keys() {
+            if local_dirs.contains(path.as_str()) {
+                plan.replaced.push(path.clone());
+            } else if !local.contains_key(path) {
+                plan.deletes.push(path.clone());
+            }
+        }
+        for dir in &remote_dirs {
+            if local.contains_key(*dir) {
+                plan.replaced.push(dir.to_string());
+            } else if !local_dirs.contains(dir) {
+                plan.deletes.push(dir.to_string());
+            }
+        }
+        plan.replaced = outermost(std::mem::take(&mut plan.replaced));
+        plan.deletes = outermost(std::mem::take(&mut plan.deletes))
+            .into_iter()
+            .filter(|path| !plan.replaced.iter().any(|r| is_within(path, r)))
+            .collect();
+
+        // Only files that stay as they are can be copied from, so applying the copies in
+        // any order never reads a file that was already replaced
+        let remote_by_hash: HashMap<&str, &str> = remote
+            .iter()
+            .filter(|(path, entry)| local.get(*path).is_none_or(|l| l.hash == entry.hash))
+            .filter(|(path, _)| !plan.replaced.iter().any(|r| is_within(path, r)))
+            .map(|(path, entry)| (entry.hash.as_str(), path.as_str()))
+            .collect();
+
+        for (path, entry) in local {
+            match remote.get(path) {
+                // Same content, a different local mtime doesn't warrant sending it again
+                Some(known) if known.hash == entry.hash => {}
+                _ => match remote_by_hash.get(entry.hash.as_str()) {
+                    Some(from) if entry.size > 0 => {
+                        plan.copies.push((from.to_string(), path.clone()))
+                    }
+                    _ => plan.uploads.push(path.clone()),
+                },
+            }
+        }
+
+        plan
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.replaced.is_empty()
+            && self.copies.is_empty()
+            && self.uploads.is_empty()
+            && self.deletes.is_empty()
+    }
+}
+
+/// Every directory containing a file of `manifest`
+fn parent_dirs(manifest: &Manifest) -> BTreeSet<&str> {
+    let mut dirs = BTreeSet::new();
+    for path in manifest.keys() {
+        let mut path = path.as_str();
+        while let Some((parent, _)) = path.rsplit_once('/') {
+            if !dirs.insert(parent) {
+                break;
+            }
+            path = parent;
+        }
+    }
+    dirs
+}
+
+/// Whether `path` is `dir` or below it
+fn is_within(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+/// `paths` without the ones below another of them, which go away with it
+fn outermost(mut paths: Vec<String>) -> Vec<String> {
+    // Parents sort before their children
+    paths.sort();
+    let mut kept: Vec<String> = Vec::new();
+    for path in paths {
+        if !kept.iter().any(|dir| is_within(&path, dir)) {
+            kept.push(path);
+        }
+    }
+    kept
+}
+
+/// Total size of the `paths` in `manifest`
+pub fn total_size(manifest: &Manifest, paths: &[String]) -> u64 {
+    paths
+        .iter()
+        .filter_map(|path| manifest.get(path))
+        .map(|entry| entry.size)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::temp_dir;
+
+    fn entry(size: u64, hash: &str) -> Entry {
+        Entry {
+            size,
+            mtime: 1,
+            hash: hash.to_string(),
+        }
+    }
+
+    fn manifest(entries: &[(&str, u64, &str)]) -> Manifest {
+        entries
+            .iter()
+            .map(|(path, size, hash)| (path.to_string(), entry(*size, hash)))
+            .collect()
+    }
+
+    #[test]
+    fn manifest_round_trip() {
+        let manifest = manifest(&[
+            ("src/main.rs", 10, "aa"),
+            ("dir with spaces/ü.txt", 0, "bb"),
+            ("link", 0, "link:../target dir"),
+        ]);
+        let content = format_manifest(&manifest);
+        assert_eq!(parse_manifest(&content), manifest);
+    }
+
+    #[test]
+    fn parse_skips_malformed_lines() {
+        let content = "aa\t1\t2\tgood\nbroken\naa\tx\t2\tbad size\naa\t1\t2\twith\ttab\n";
+        let parsed = parse_manifest(content);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(
+            parsed["good"],
+            Entry {
+                size: 1,
+                mtime: 2,
+                hash: "aa".to_string()
+            }
+        );
+        assert_eq!(parsed["with\ttab"].hash, "aa");
+    }
+
+    #[test]
+    fn plan_of_identical_manifests_is_empty() {
+        let local = manifest(&[("a", 1, "h1"), ("b", 2, "h2")]);
+        let mut remote = local.clone();
+        // A different mtime alone doesn't send the file again
+        remote.get_mut("a").unwrap().mtime = 99;
+        assert!(Plan::new(&local, &remote).is_empty());
+    }
+
+    #[test]
+    fn plan_uploads_new_and_modified_files() {
+        let local = manifest(&[("new", 1, "h1"), ("changed", 2, "h2"), ("same", 3, "h3")]);
+        let remote = manifest(&[("changed", 2, "old"), ("same", 3, "h3")]);
+        let plan = Plan::new(&local, &remote);
+        assert!(plan.copies.is_empty());
+        assert_eq!(plan.uploads, vec!["changed", "new"]);
+        assert!(plan.deletes.is_empty());
+    }
+
+    #[test]
+    fn plan_deletes_removed_files() {
+        let local = manifest(&[("kept", 1, "h1")]);
+        let remote = manifest(&[("kept", 1, "h1"), ("gone", 2, "h2")]);
+        let plan = Plan::new(&local, &remote);
+        assert_eq!(plan.deletes, vec!["gone"]);
+        assert!(plan.copies.is_empty() && plan.uploads.is_empty());
+    }
+
+    #[test]
+    fn plan_copies_renamed_files() {
+        let local = manifest(&[("new_name.rs", 5, "h1")]);
+        let remote = manifest(&[("old_name.rs", 5, "h1")]);
+        let plan = Plan::new(&local, &remote);
+        assert_eq!(
+            plan.copies,
+            vec![("old_name.rs".to_string(), "new_name.rs".to_string())]
+        );
+        assert!(plan.uploads.is_empty());
+        assert_eq!(plan.deletes, vec!["old_name.rs"]);
+    }
+
+    #[test]
+    fn plan_never_copies_from_replaced_files() {
+        // `a` gets new content, so its old content cannot be copied to `b`
+        let local = manifest(&[("a", 5, "new"), ("b", 5, "old")]);
+        let remote = manifest(&[("a", 5, "old")]);
+        let plan = Plan::new(&local, &remote);
+        assert!(plan.copies.is_empty());
+        assert_eq!(plan.uploads, vec!["a", "b"]);
+    }
+
+    #[test]
+    fn plan_sends_empty_files_and_links() {
+        let local = manifest(&[("empty2", 0, "e"), ("link2", 0, "link:target")]);
+        let remote = manifest(&[("empty", 0, "e"), ("link", 0, "link:target")]);
+        let plan = Plan::new(&local, &remote);
+        assert!(plan.copies.is_empty());
+        assert_eq!(plan.uploads, vec!["empty2", "link2"]);
+    }
+
+    #[test]
+    fn plan_replaces_file_with_link() {
+        let local = manifest(&[("config", 0, "link:config.dev")]);
+        let remote = manifest(&[("config", 12, "h1")]);
+        let plan = Plan::new(&local, &remote);
+        assert_eq!(plan.uploads, vec!["config"]);
+        assert!(plan.deletes.is_empty());
+    }
+
+    #[test]
+    fn plan_removes_emptied_directories() {
+        let local = manifest(&[("src/main.rs", 1, "h1")]);
+        let remote = manifest(&[
+            ("src/main.rs", 1, "h1"),
+            ("src/old/mod.rs", 2, "h2"),
+            ("src/old/nested/deep.rs", 3, "h3"),
+            ("src/gone.rs", 4, "h4"),
+        ]);
+        let plan = Plan::new(&local, &remote);
+        assert_eq!(plan.deletes, vec!["src/gone.rs", "src/old"]);
+        assert!(plan.replaced.is_empty());
+    }
+
+    #[test]
+    fn plan_replaces_directory_with_file() {
+        let local = manifest(&[("a", 1, "h1"), ("a b", 2, "h2")]);
+        let remote = manifest(&[("a/x", 1, "h1"), ("a/y/z", 3, "h3"), ("a b", 2, "h2")]);
+        let plan = Plan::new(&local, &remote);
+        assert_eq!(plan.replaced, vec!["a"]);
+        assert!(plan.deletes.is_empty());
+        // `a/x` is removed before it could be copied from
+        assert!(plan.copies.is_empty());
+        assert_eq!(plan.uploads, vec!["a"]);
+    }
+
+    #[test]
+    fn plan_replaces_file_with_directory() {
+        let local = manifest(&[("a/x", 1, "h1"), ("a/y/z", 2, "h2")]);
+        let remote = manifest(&[("a", 1, "h1"), ("b/y", 3, "h3")]);
+        let plan = Plan::new(&local, &remote);
+        assert_eq!(plan.replaced, vec!["a"]);
+        assert_eq!(plan.deletes, vec!["b"]);
+        assert!(plan.copies.is_empty());
+        assert_eq!(plan.uploads, vec!["a/x", "a/y/z"]);
+    }
+
+    #[test]
+    fn total_size_of_paths() {
+        let local = manifest(&[("a", 3, "h1"), ("b", 4, "h2"), ("c", 5, "h3")]);
+        let paths = vec!["a".to_string(), "c".to_string(), "missing".to_string()];
+        assert_eq!(total_size(&local, &paths), 8);
+    }
+
+    #[test]
+    fn scan_hashes_files_and_records_links() {
+        let root = temp_dir("delta-scan");
+        fs::create_dir_all(root.join("src")).unwrap();
+        fs::write(root.join("src/main.rs"), "fn main() {}\n").unwrap();
+        fs::write(root.join("notes.log"), "excluded").unwrap();
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink("src/main.rs", root.join("main.rs")).unwrap();
+            std::os::unix::fs::symlink("src", root.join("sources")).unwrap();
+        }
+
+        let manifest = scan(
+            &root,
+            &[&root],
+            false,
+            &["*.log".to_string()],
+            &Manifest::new(),
+        );
+        fs::remove_dir_all(&root).unwrap();
+        let manifest = manifest.unwrap();
+
+        let main = &manifest["src/main.rs"];
+        assert_eq!(main.size, 13);
+        assert_eq!(
+            main.hash,
+            "536e506bb90914c243a12b397b9a998f85ae2cbd9ba02dfd03a9e155ca5ca0f4"
+        );
+        assert!(!manifest.contains_key("notes.log"));
+        #[cfg(unix)]
+        {
+            assert_eq!(manifest["main.rs"].hash, "link:src/main.rs");
+            assert_eq!(manifest["main.rs"].size, 0);
+            // Links to directories are not followed
+            assert_eq!(manifest["sources"].hash, "link:src");
+            assert!(!manifest.contains_key("sources/main.rs"));
+        }
+    }
+
+    #[test]
+    fn scan_keeps_known_hashes() {
+        let root = temp_dir("delta-previous");
+        fs::write(root.join("file"), "content").unwrap();
+
+        let first = scan(&root, &[&root], false, &[], &Manifest::new()).unwrap();
+        let mut previous = first.clone();
+        previous.get_mut("file").unwrap().hash = "recorded".to_string();
+        let unchanged = scan(&root, &[&root], false, &[], &previous).unwrap();
+
+        previous.get_mut("file").unwrap().mtime += 1;
+        let touched = scan(&root, &[&root], false, &[], &previous).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(unchanged["file"].hash, "recorded");
+        assert_eq!(touched["file"].hash, first["file"].hash);
+    }
+}
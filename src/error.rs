@@ -0,0 +1,67 @@
+use std::fmt;
+use std::process::ExitStatus;
+
+/// Exit code when ssh cannot reach the host or the connection drops (same as ssh itself)
+pub const EXIT_TRANSPORT: i32 = 255;
+/// Exit code when syncing the sources to the remote fails
+pub const EXIT_SYNC: i32 = 254;
+/// Exit code when copying artifacts back from the remote fails
+pub const EXIT_ARTIFACTS: i32 = 253;
+/// Exit code when the remote project lock cannot be acquired in time
+pub const EXIT_LOCK: i32 = 252;
+
+/// Failures that are reported with a dedicated process exit code
+#[derive(Debug)]
+pub enum OffloadError {
+    /// The remote command ran and exited with a non-zero status, which is passed on as is
+    Remote { command: String, code: i32 },
+    /// The SSH connection could not be established or was lost
+    Transport(String),
+    /// Syncing the sources to the remote failed
+    Sync(String),
+    /// Copying artifacts back from the remote failed
+    Artifacts(String),
+    /// Another invocation held the remote lock for too long
+    Lock(String),
+}
+
+impl OffloadError {
+    /// Classify the exit status of an `ssh <host> <command>` invocation
+    pub fn from_ssh_status(command: &str, status: ExitStatus) -> Self {
+        match status.code() {
+            Some(EXIT_TRANSPORT) | None => {
+                OffloadError::Transport(format!("ssh {} while running `{}`", status, command))
+            }
+            Some(code) => OffloadError::Remote {
+                command: command.to_string(),
+                code,
+            },
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            OffloadError::Remote { code, .. } => *code,
+            OffloadError::Transport(_) => EXIT_TRANSPORT,
+            OffloadError::Sync(_) => EXIT_SYNC,
+            OffloadError::Artifacts(_) => EXIT_ARTIFACTS,
+            OffloadError::Lock(_) => EXIT_LOCK,
+        }
+    }
+}
+
+impl fmt::Display for OffloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OffloadError::Remote { command, code } => {
+                write!(f, "Remote command exited with code {}: {}", code, command)
+            }
+            OffloadError::Transport(msg) => write!(f, "SSH connection failed: {}", msg),
+            OffloadError::Sync(msg) => write!(f, "Syncing sources failed: {}", msg),
+            OffloadError::Artifacts(msg) => write!(f, "Copying artifacts failed: {}", msg),
+            OffloadError::Lock(msg) => write!(f, "{}", msg),
+        }
+    }
+}
+
+impl std::error::Error for OffloadError {}
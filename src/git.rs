@@ -0,0 +1,124 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::time::UNIX_EPOCH;
+
+use anyhow::{bail, Context, Result};
+
+use crate::util::stable_hash;
+
+/// The local git repository a project lives in
+#[derive(Debug, Clone)]
+pub struct GitRepo {
+    pub toplevel: PathBuf,
+}
+
+impl GitRepo {
+    /// The repository containing `dir`, `None` outside of git or if git is not installed
+    pub fn discover(dir: &Path) -> Option<GitRepo> {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["rev-parse", "--show-toplevel"])
+            .stderr(Stdio::null())
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+
+        let toplevel = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+        Some(GitRepo {
+            toplevel: toplevel.canonicalize().unwrap_or(toplevel),
+        })
+    }
+
+    fn git(&self) -> Command {
+        let mut cmd = Command::new("git");
+        cmd.arg("-C").arg(&self.toplevel);
+        cmd
+    }
+
+    fn output(&self, args: &[&str]) -> Result<Vec<u8>> {
+        let output = self
+            .git()
+            .args(args)
+            .output()
+            .context("Executing git failed")?;
+        if !output.status.success() {
+            bail!(
+                "`git {}` failed: {}",
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        Ok(output.stdout)
+    }
+
+    /// Commit id of `HEAD`
+    pub fn head(&self) -> Result<String> {
+        let head = self.output(&["rev-parse", "--verify", "HEAD"])?;
+        Ok(String::from_utf8_lossy(&head).trim().to_string())
+    }
+
+    pub fn has_commit(&self, commit: &str) -> bool {
+        self.git()
+            .args(["cat-file", "-e", &format!("{}^{{commit}}", commit)])
+            .stderr(Stdio::null())
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    pub fn is_ancestor(&self, ancestor: &str, commit: &str) -> bool {
+        self.git()
+            .args(["merge-base", "--is-ancestor", ancestor, commit])
+            .status()
+            .is_ok_and(|status| status.success())
+    }
+
+    /// `git bundle` writing the history of `HEAD` to stdout, leaving out what `known` has
+    pub fn bundle_command(&self, known: Option<&str>) -> Command {
+        let mut cmd = self.git();
+        cmd.args(["bundle", "create", "-q", "-", "HEAD"]);
+        if let Some(known) = known {
+            cmd.arg(format!("^{}", known));
+        }
+        cmd
+    }
+
+    /// Changes of the working tree against `base`, staged or not, as a binary patch
+    pub fn diff(&self, base: &str) -> Result<Vec<u8>> {
+        self.output(&["diff", "--binary", base])
+    }
+
+    /// Files git doesn't track that aren't ignored either, relative to the top level
+    pub fn untracked_files(&self) -> Result<Vec<PathBuf>> {
+        let output = self.output(&["ls-files", "-z", "--others", "--exclude-standard"])?;
+        Ok(output
+            .split(|b| *b == 0)
+            .filter(|name| !name.is_empty())
+            .map(|name| PathBuf::from(String::from_utf8_lossy(name).to_string()))
+            .collect())
+    }
+
+    /// Identifies what a sync with these inputs produces, to skip syncs that change nothing
+    pub fn sync_state(&self, base: &str, diff: &[u8], untracked: &[PathBuf]) -> u64 {
+        let mut key = Vec::new();
+        key.extend_from_slice(base.as_bytes());
+        key.extend_from_slice(&stable_hash(diff).to_le_bytes());
+        for file in untracked {
+            key.extend_from_slice(file.to_string_lossy().as_bytes());
+            if let Ok(metadata) = fs::metadata(self.toplevel.join(file)) {
+                let modified = metadata
+                    .modified()
+                    .ok()
+                    .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                    .unwrap_or_default();
+                key.extend_from_slice(&metadata.len().to_le_bytes());
+                key.extend_from_slice(&modified.as_nanos().to_le_bytes());
+            }
+        }
+        stable_hash(&key)
+    }
+}
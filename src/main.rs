@@ -1,23 +1,48 @@
-use clap::{Parser, Subcommand};
-use log::{debug, info};
+use clap::{ArgAction, Parser, Subcommand};
+use log::{debug, info, warn};
 use std::path::Path;
 use std::time::Instant;
 
+mod artifact;
+mod cargo_config;
+mod config;
+use config::{Config, HostConfig};
+mod container;
+mod delta;
+
+mod error;
+use error::OffloadError;
+mod git;
+mod manifest;
+use manifest::{locate_manifest, locate_workspace_root};
+mod metadata;
+use metadata::{package_arg, Metadata};
+#[cfg(feature = "native-ssh")]
+mod native_ssh;
+
 mod offload;
 use offload::CargoOffload;
 
+mod pool;
+
+mod rewrite;
+mod sccache;
+mod sync_filter;
+mod transfer;
+mod transport;
+
 mod util;
 use util::*;
 
 #[derive(Parser)]
 #[command(name = "offload")]
 #[command(about = "A CLI tool for remote Rust compilation")]
-#[command(disable_help_subcommand = true)]
+#[command(disable_help_subcommand = true, disable_help_flag = true)]
 pub struct Cli {
     #[command(subcommand)]
     command: Commands,
 
-    /// SSH host (user@hostname or just hostname)
+    /// SSH host (user@hostname or just hostname), or `local:<dir>` to build in a local directory
     #[arg(short, long, global = true)]
     host: Option<String>,
 
@@ -25,6 +50,10 @@ pub struct Cli {
     #[arg(short, long, global = true)]
     port: Option<u16>,
 
+    /// Named host from the `[hosts]` config section (can also be set via CARGO_OFFLOAD_PROFILE_HOST)
+    #[arg(long = "profile-host", global = true)]
+    profile_host: Option<String>,
+
     /// Target triple (defaults to x86_64-unknown-linux-gnu)
     #[arg(long, global = true)]
     target: Option<String>,
@@ -40,6 +69,10 @@ pub struct Cli {
     /// Forward ports from remote to local (format: local_port:remote_port)
     #[arg(short = 'L', long = "forward", global = true)]
     forward_ports: Vec<String>,
+
+    /// Print help (`-h` is `--host`)
+    #[arg(long, global = true, action = ArgAction::Help)]
+    help: Option<bool>,
 }
 
 #[derive(Subcommand)]
@@ -79,6 +112,13 @@ pub enum Commands {
         args: Vec<String>,
     },
 
+    /// Check the project on remote (supports --message-format=json for editor integration)
+    Check {
+        /// All arguments to pass to cargo check
+        #[arg(allow_hyphen_values = true)]
+        args: Vec<String>,
+    },
+
     /// Run clippy on remote
     Clippy {
         /// All arguments to pass to cargo clippy
@@ -86,6 +126,13 @@ pub enum Commands {
         args: Vec<String>,
     },
 
+    /// Run any cargo subcommand on remote (e.g. `offload cargo nextest run`)
+    Cargo {
+        /// Cargo subcommand followed by its arguments
+        #[arg(allow_hyphen_values = true, trailing_var_arg = true, required = true)]
+        args: Vec<String>,
+    },
+
     /// Execute rustup toolchain commands on remote
     Toolchain {
         /// Arguments to pass to rustup toolchain
@@ -93,8 +140,79 @@ pub enum Commands {
         args: Vec<String>,
     },
 
+    /// Sync the sources to the remote without building
+    Sync {
+        /// List the files that would be transferred and their size
+        #[arg(long)]
+        dry_run: bool,
+    },
+
     /// Clean remote build directory and local binaries
     Clean,
+
+    /// Show the remote directory and host used for this project
+    Status,
+
+    /// Close the shared ssh connection to the build host (all hosts of a pool)
+    Disconnect,
+
+    /// Inspect configured build hosts
+    Hosts {
+        #[command(subcommand)]
+        command: HostsCommands,
+    },
+
+    /// Inspect the caches on the build host
+    Cache {
+        #[command(subcommand)]
+        command: CacheCommands,
+    },
+
+    /// Unknown subcommands are passed on to cargo like `offload cargo <subcommand>`
+    #[command(external_subcommand)]
+    External(Vec<String>),
+}
+
+impl Commands {
+    /// Arguments of commands that are passed on to a cargo command
+    pub fn cargo_args(&self) -> Option<&[String]> {
+        match self {
+            Commands::Build { args }
+            | Commands::Run { args }
+            | Commands::RunLocal { args }
+            | Commands::RunRemote { args }
+            | Commands::Test { args }
+            | Commands::Check { args }
+            | Commands::Clippy { args }
+            | Commands::Cargo { args }
+            | Commands::External(args) => Some(args),
+            _ => None,
+        }
+    }
+
+    /// Whether the command acts on what the last build left on its host, rather than
+    /// picking the least busy host of the pool
+    pub fn uses_last_build_host(&self) -> bool {
+        matches!(
+            self,
+            Commands::Toolchain { .. }
+                | Commands::Status
+                | Commands::Cache { .. }
+                | Commands::Sync { .. }
+        )
+    }
+}
+
+#[derive(Subcommand)]
+pub enum CacheCommands {
+    /// Show the size of the remote cargo home (registry, git checkouts) and sccache cache
+    Stats,
+}
+
+#[derive(Subcommand)]
+pub enum HostsCommands {
+    /// List named hosts from the configuration
+    List,
 }
 
 fn check_prerequisites() -> Result<String, Box<dyn std::error::Error>> {
@@ -136,40 +254,144 @@ fn check_prerequisites() -> Result<String, Box<dyn std::error::Error>> {
                 }
             }
         }
+        // Only needed by the rsync sync method, which reports the failure when it runs
         Ok(_) => {
-            eprintln!("Error: rsync is installed but not working properly.");
-            eprintln!();
-            eprintln!("Please ensure rsync is properly installed and accessible in your PATH.");
-            return Err("rsync check failed".into());
+            debug!("rsync is installed but not working properly");
+            "--progress"
         }
         Err(_) => {
-            eprintln!("Error: rsync is not installed or not found in PATH.");
-            eprintln!();
-            eprintln!("rsync is required for cargo-offload to sync files to the remote server.");
-            return Err("rsync not found".into());
+            debug!("rsync is not installed, only the builtin sync method is available");
+            "--progress"
         }
     };
 
-    // Check if ssh is installed
+    // Check if ssh is installed. Only needed by the OpenSSH client, which reports the
+    // failure when it runs
     match std::process::Command::new("ssh").arg("-V").output() {
         Ok(output) if output.status.success() => {
             debug!("ssh is available");
         }
         Ok(_) => {
-            eprintln!("Warning: ssh is installed but may not be working properly.");
+            debug!("ssh is installed but not working properly");
         }
         Err(_) => {
-            eprintln!("Warning: ssh is not installed or not found in PATH.");
-            eprintln!("SSH is required for connecting to the remote server.");
+            debug!("ssh is not installed, only the native SSH client is available");
         }
     }
 
     Ok(progress_flag.to_string())
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+fn print_hosts(config: &Config) {
+    if config.hosts.is_empty() {
+        println!("No hosts configured");
+        return;
+    }
+
+    let rows: Vec<[String; 5]> = config
+        .hosts
+        .iter()
+        .map(|(name, profile)| {
+            let host = profile.resolve(name);
+            [
+                name.clone(),
+                format!("{}:{}", host.address, host.port),
+                host.target.unwrap_or_else(|| "-".to_string()),
+                host.jobs
+                    .map(|jobs| jobs.to_string())
+                    .unwrap_or_else(|| "-".to_string()),
+                host.remote_base_dir,
+            ]
+        })
+        .collect();
+
+    let header = ["NAME", "HOST", "TARGET", "JOBS", "REMOTE DIR"];
+    let mut widths = header.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    let format_row = |marker: &str, cells: &[&str]| {
+        let cells = cells
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect::<Vec<_>>();
+        format!("{} {}", marker, cells.join("  "))
+            .trim_end()
+            .to_string()
+    };
+
+    println!("{}", format_row(" ", &header));
+    for row in &rows {
+        let selected = config.profile_host.as_deref() == Some(row[0].as_str());
+        let cells = row.iter().map(String::as_str).collect::<Vec<_>>();
+        println!("{}", format_row(if selected { "*" } else { " " }, &cells));
+    }
+}
+
+/// Ask the master of the shared connection to `host` to exit, if there is one
+fn disconnect(host: &HostConfig) -> Result<(), Box<dyn std::error::Error>> {
+    if host.local {
+        println!("{} is not reached over ssh", host.display_name());
+        return Ok(());
+    }
+    if host.control_persist.is_none() {
+        println!("ssh connection sharing is disabled");
+        return Ok(());
+    }
+
+    let control = |operation: &str| {
+        std::process::Command::new("ssh")
+            .args(host.ssh_options())
+            .args(["-O", operation])
+            .arg(&host.address)
+            .stdout(std::process::Stdio::null())
+            .stderr(std::process::Stdio::null())
+            .status()
+    };
+
+    if !control("check")?.success() {
+        println!("No open connection to {}", host.display_name());
+        return Ok(());
+    }
+    if !control("exit")?.success() {
+        return Err(OffloadError::Transport(format!(
+            "cannot close the shared connection to {}",
+            host.display_name()
+        ))
+        .into());
+    }
+
+    println!("Closed connection to {}", host.display_name());
+    Ok(())
+}
+
+fn main() {
     env_logger::init_from_env(env_logger::Env::new().default_filter_or("warn"));
 
+    if let Err(err) = run() {
+        match err.downcast_ref::<OffloadError>() {
+            Some(OffloadError::Remote { command, code }) => {
+                // The remote command already reported its own errors
+                debug!("Remote command exited with code {}: {}", code, command);
+                std::process::exit(*code);
+            }
+            Some(offload_err) => {
+                eprintln!("Error: {}", offload_err);
+                std::process::exit(offload_err.exit_code());
+            }
+            None => {
+                eprintln!("Error: {}", err);
+                std::process::exit(1);
+            }
+        }
+    }
+}
+
+fn run() -> Result<(), Box<dyn std::error::Error>> {
     // Perform preflight checks and get the appropriate progress flag
     let progress_flag = check_prerequisites()?;
 
@@ -191,19 +413,71 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     };
 
-    // Verify we're in a Rust project
-    if !Path::new("Cargo.toml").exists() {
-        return Err("Not in a Rust project directory (Cargo.toml not found)".into());
+    // Commands that only inspect the configuration don't need a Rust project
+    if let Commands::Hosts { command } = &cli.command {
+        let current_dir = std::env::current_dir()?;
+        let project_root = locate_workspace_root(&current_dir, None).unwrap_or(current_dir);
+        let config = Config::load(&project_root, &cli)?;
+        match command {
+            HostsCommands::List => print_hosts(&config),
+        }
+        return Ok(());
     }
 
-    let offload = CargoOffload::new(&cli, toolchain, progress_flag)?;
+    if let Commands::Disconnect = &cli.command {
+        let current_dir = std::env::current_dir()?;
+        let project_root = locate_workspace_root(&current_dir, None).unwrap_or(current_dir);
+        let mut config = Config::load(&project_root, &cli)?;
+        config.prepare_ssh_multiplex();
+        let hosts = if config.pool.is_empty() {
+            vec![config.selected_host()?]
+        } else {
+            config.pool_hosts()?
+        };
+        for host in hosts {
+            disconnect(&host)?;
+        }
+        return Ok(());
+    }
+
+    // Any pool host may hold a copy of the project from an earlier build
+    if let Commands::Clean = &cli.command {
+        let current_dir = std::env::current_dir()?;
+        let project_root = locate_workspace_root(&current_dir, None)?;
+        let config = Config::load(&project_root, &cli)?;
+        if !config.pool.is_empty() {
+            for host in config.pool_hosts()? {
+                let name = host.display_name();
+                let cleaned =
+                    CargoOffload::new(&cli, toolchain.clone(), progress_flag.clone(), Some(host))
+                        .and_then(|offload| {
+                            let _lock = offload.lock()?;
+                            offload.clean()
+                        });
+                match cleaned {
+                    Err(e) if matches!(e.downcast_ref(), Some(OffloadError::Transport(_))) => {
+                        warn!("Skipping unreachable host {}: {}", name, e)
+                    }
+                    cleaned => cleaned?,
+                }
+            }
+            info!(
+                "Clean completed successfully (took {})",
+                format_duration(start_time.elapsed())
+            );
+            return Ok(());
+        }
+    }
+
+    let offload = CargoOffload::new(&cli, toolchain, progress_flag, None)?;
 
     match cli.command {
         Commands::Build { args } => {
+            let _lock = offload.lock()?;
             offload.sync_source()?;
             offload.setup_toolchain()?;
-            offload.run_cargo_command("build", &args, &cli.env_vars, &[])?;
-            offload.copy_artifacts(&args, None, None)?;
+            let artifacts = offload.build(&args)?;
+            offload.copy_artifacts(&args, &artifacts)?;
             let elapsed = start_time.elapsed();
             info!(
                 "Build completed and artifacts copied successfully (took {})",
@@ -215,9 +489,28 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             let (build_args, run_args) = separate_run_args_from_raw(&args);
 
             // manually parse args
-            let bin = parse_flag(&build_args, "bin")?;
+            let mut bin = parse_flag(&build_args, "bin")?;
             let example = parse_flag(&build_args, "example")?;
 
+            // Pick the binary like `cargo run` does, so that only this one gets built
+            if bin.is_none() && example.is_none() {
+                let current_dir = std::env::current_dir()?;
+                let manifest_path = parse_flag(&build_args, "manifest-path")?;
+                let manifest =
+                    locate_manifest(&current_dir, manifest_path.as_deref().map(Path::new))?;
+                match Metadata::load(&manifest) {
+                    Ok(metadata) => {
+                        let package = package_arg(&build_args);
+                        let default_bin =
+                            metadata.default_run_binary(&manifest, package.as_deref())?;
+                        debug!("Default binary to run: {}", default_bin);
+                        bin = Some(default_bin);
+                    }
+                    Err(e) => warn!("Cannot determine the default binary to run: {:#}", e),
+                }
+            }
+
+            let lock = offload.lock()?;
             offload.sync_source()?;
             offload.setup_toolchain()?;
 
@@ -231,58 +524,48 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
                 final_build_args.push(example_name.clone());
             }
 
-            offload.run_cargo_command("build", &final_build_args, &cli.env_vars, &[])?;
-            let artifacts =
-                offload.copy_artifacts(&final_build_args, bin.as_ref(), example.as_ref())?;
-
-            let artifact_to_run = if let Some(example_name) = &example {
-                artifacts
-                    .into_iter()
-                    .find(|p| {
-                        p.parent()
-                            .and_then(|parent| parent.file_name())
-                            .map(|name| name == "examples")
-                            .unwrap_or(false)
-                            && p.file_name().unwrap().to_string_lossy() == *example_name
-                    })
-                    .ok_or_else(|| format!("Example '{}' not found", example_name))?
-            } else if let Some(bin_name) = &bin {
-                artifacts
-                    .into_iter()
-                    .find(|p| p.file_name().unwrap().to_string_lossy() == *bin_name)
-                    .ok_or_else(|| format!("Binary '{}' not found", bin_name))?
-            } else {
-                // Find the first binary (not example or library)
-                let binaries: Vec<_> = artifacts
-                    .into_iter()
-                    .filter(|p| {
-                        !p.parent()
-                            .and_then(|parent| parent.file_name())
-                            .map(|name| name == "examples")
-                            .unwrap_or(false)
-                            && !p.file_name().unwrap().to_string_lossy().starts_with("lib")
-                    })
-                    .collect();
-                debug!(
-                    "found binaries: {}",
-                    binaries
-                        .iter()
-                        .map(|p| p.to_string_lossy())
-                        .collect::<Vec<_>>()
-                        .join(", ")
-                );
+            let artifacts = offload.build(&final_build_args)?;
+            let artifacts = offload.copy_artifacts(&final_build_args, &artifacts)?;
+
+            let (kind, name) = match (&bin, &example) {
+                (_, Some(example_name)) => ("example", Some(example_name)),
+                (Some(bin_name), None) => ("bin", Some(bin_name)),
+                (None, None) => ("bin", None),
+            };
+            let candidates: Vec<_> = artifacts
+                .into_iter()
+                .filter(|a| a.is_runnable(kind))
+                .filter(|a| name.is_none_or(|name| a.target.name == *name))
+                .collect();
+            debug!(
+                "found {}s: {}",
+                kind,
+                candidates
+                    .iter()
+                    .map(|a| a.target.name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
 
-                if binaries.len() == 1 {
-                    binaries.into_iter().next().unwrap()
-                } else if binaries.is_empty() {
-                    return Err("No binaries found to run".into());
-                } else {
-                    // TODO: determine default binary to run
+            let artifact_to_run = match (candidates.len(), name) {
+                (1, _) => candidates.into_iter().next().unwrap(),
+                (0, Some(name)) if kind == "example" => {
+                    return Err(format!("Example '{}' not found", name).into())
+                }
+                (0, Some(name)) => return Err(format!("Binary '{}' not found", name).into()),
+                (0, None) => return Err("No binaries found to run".into()),
+                _ => {
                     return Err(
                         "Multiple binaries found. Use --bin to specify which one to run".into(),
                     );
                 }
             };
+            let artifact_to_run = artifact_to_run
+                .executable
+                .expect("candidates are executable");
+
+            // Other builds may use the remote directory while the binary runs locally
+            drop(lock);
 
             offload.run_binary(&artifact_to_run, &run_args)?;
             let elapsed = start_time.elapsed();
@@ -293,9 +576,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
 
         Commands::RunRemote { args } => {
+            let _lock = offload.lock()?;
             offload.sync_source()?;
             offload.setup_toolchain()?;
-            offload.run_cargo_command("run", &args, &cli.env_vars, &cli.forward_ports)?;
+            offload.run_cargo_command("run", &args, offload.forward_ports())?;
             let elapsed = start_time.elapsed();
             info!(
                 "Remote run completed successfully (took {})",
@@ -304,9 +588,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
 
         Commands::Test { args } => {
+            let _lock = offload.lock()?;
             offload.sync_source()?;
             offload.setup_toolchain()?;
-            offload.run_cargo_command("test", &args, &cli.env_vars, &[])?;
+            offload.run_cargo_command("test", &args, &[])?;
             let elapsed = start_time.elapsed();
             info!(
                 "Tests completed successfully (took {})",
@@ -314,10 +599,23 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             );
         }
 
+        Commands::Check { args } => {
+            let _lock = offload.lock()?;
+            offload.sync_source()?;
+            offload.setup_toolchain()?;
+            offload.run_cargo_command("check", &args, &[])?;
+            let elapsed = start_time.elapsed();
+            info!(
+                "Check completed successfully (took {})",
+                format_duration(elapsed)
+            );
+        }
+
         Commands::Clippy { args } => {
+            let _lock = offload.lock()?;
             offload.sync_source()?;
             offload.setup_toolchain()?;
-            offload.run_cargo_command("clippy", &args, &cli.env_vars, &[])?;
+            offload.run_cargo_command("clippy", &args, &[])?;
             let elapsed = start_time.elapsed();
             info!(
                 "Clippy completed successfully (took {})",
@@ -325,11 +623,52 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             );
         }
 
+        Commands::Cargo { args } | Commands::External(args) => {
+            let _lock = offload.lock()?;
+            offload.sync_source()?;
+            offload.setup_toolchain()?;
+            offload.run_cargo_passthrough(&args)?;
+            offload.copy_command_artifacts(&args[0])?;
+            let elapsed = start_time.elapsed();
+            info!(
+                "Cargo {} completed successfully (took {})",
+                args[0],
+                format_duration(elapsed)
+            );
+        }
+
         Commands::Toolchain { args } => {
             offload.toolchain_remote(&args)?;
         }
 
+        Commands::Status => {
+            offload.status()?;
+        }
+
+        Commands::Cache { command } => match command {
+            CacheCommands::Stats => offload.cache_stats()?,
+        },
+
+        Commands::Hosts { .. } | Commands::Disconnect => {
+            unreachable!("handled before project detection")
+        }
+
+        Commands::Sync { dry_run } => {
+            if dry_run {
+                offload.sync_dry_run()?;
+            } else {
+                let _lock = offload.lock()?;
+                offload.sync_source()?;
+                let elapsed = start_time.elapsed();
+                info!(
+                    "Sync completed successfully (took {})",
+                    format_duration(elapsed)
+                );
+            }
+        }
+
         Commands::Clean => {
+            let _lock = offload.lock()?;
             offload.clean()?;
             let elapsed = start_time.elapsed();
             info!(
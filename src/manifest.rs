@@ -0,0 +1,348 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use log::{debug, warn};
+
+/// Dependency tables that may contain `path` dependencies, relative to a manifest's root,
+/// a `[target.<cfg>]` table or `[workspace]`
+const DEPENDENCY_TABLES: &[&str] = &[
+    "dependencies",
+    "dev-dependencies",
+    "dev_dependencies",
+    "build-dependencies",
+    "build_dependencies",
+];
+
+fn read_manifest(path: &Path) -> Result<toml::Table> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Cannot open {}", path.display()))?;
+    toml::from_str(&content).with_context(|| format!("Cannot parse {}", path.display()))
+}
+
+/// Manifest cargo uses when run in `dir`: `manifest_path` if given, otherwise the nearest
+/// `Cargo.toml` in `dir` or above
+pub fn locate_manifest(dir: &Path, manifest_path: Option<&Path>) -> Result<PathBuf> {
+    let manifest = match manifest_path {
+        Some(path) => dir.join(path),
+        None => dir
+            .ancestors()
+            .map(|ancestor| ancestor.join("Cargo.toml"))
+            .find(|manifest| manifest.is_file())
+            .context("Not in a Rust project directory (Cargo.toml not found)")?,
+    };
+    manifest
+        .canonicalize()
+        .with_context(|| format!("Cannot resolve {}", manifest.display()))
+}
+
+/// Root of the cargo workspace the project in `dir` belongs to, found like cargo does.
+///
+/// Starting at `manifest_path` or the nearest `Cargo.toml` above `dir`, that manifest is
+/// the root if it has a `[workspace]` table. Otherwise `package.workspace` points to the
+/// root, or the closest parent manifest with a `[workspace]` that doesn't exclude it.
+pub fn locate_workspace_root(dir: &Path, manifest_path: Option<&Path>) -> Result<PathBuf> {
+    let manifest = locate_manifest(dir, manifest_path)?;
+    let package_dir = manifest
+        .parent()
+        .context("Manifest path has no parent directory")?
+        .to_path_buf();
+
+    let parsed = read_manifest(&manifest)?;
+    if parsed.contains_key("workspace") {
+        return Ok(package_dir);
+    }
+
+    let explicit_root = parsed
+        .get("package")
+        .and_then(|package| package.get("workspace"))
+        .and_then(|workspace| workspace.as_str());
+    if let Some(root) = explicit_root {
+        let root = package_dir.join(root);
+        return root
+            .canonicalize()
+            .with_context(|| format!("Cannot resolve workspace root {}", root.display()));
+    }
+
+    for ancestor in package_dir.ancestors().skip(1) {
+        let candidate = ancestor.join("Cargo.toml");
+        if !candidate.is_file() {
+            continue;
+        }
+
+        let Some(workspace) = read_manifest(&candidate)?.remove("workspace") else {
+            continue;
+        };
+
+        // The first workspace found is the only one cargo considers
+        let relative = package_dir.strip_prefix(ancestor)?;
+        let excluded = workspace
+            .get("exclude")
+            .and_then(|exclude| exclude.as_array())
+            .is_some_and(|exclude| {
+                exclude
+                    .iter()
+                    .filter_map(|path| path.as_str())
+                    .any(|path| relative.starts_with(path))
+            });
+        if excluded {
+            break;
+        }
+
+        debug!("Found workspace root {}", ancestor.display());
+        return Ok(ancestor.to_path_buf());
+    }
+
+    Ok(package_dir)
+}
+
+/// Directories outside of `root` that the workspace depends on through `path` dependencies,
+/// including their own workspace roots. Nested directories are folded into their parent.
+pub fn external_path_dependencies(root: &Path) -> Result<Vec<PathBuf>> {
+    let mut visited = BTreeSet::new();
+    let mut external = BTreeSet::new();
+    let mut queue = vec![root.to_path_buf()];
+
+    while let Some(dir) = queue.pop() {
+        let Ok(dir) = dir.canonicalize() else {
+            warn!("Skipping missing path dependency {}", dir.display());
+            continue;
+        };
+        if !visited.insert(dir.clone()) {
+            continue;
+        }
+
+        let manifest_path = dir.join("Cargo.toml");
+        let manifest = match read_manifest(&manifest_path) {
+            Ok(manifest) => manifest,
+            Err(e) => {
+                warn!("Skipping path dependency: {:#}", e);
+                continue;
+            }
+        };
+
+        if !dir.starts_with(root) {
+            // Workspace inheritance needs the dependency's own workspace root as well
+            if let Ok(dep_root) = locate_workspace_root(&dir, None) {
+                if !root.starts_with(&dep_root) {
+                    queue.push(dep_root.clone());
+                    external.insert(dep_root);
+                }
+            }
+            external.insert(dir.clone());
+        }
+
+        queue.extend(
+            path_dependencies(&manifest)
+                .into_iter()
+                .map(|path| dir.join(path)),
+        );
+        queue.extend(workspace_members(&dir, &manifest));
+    }
+
+    let mut folded: Vec<PathBuf> = Vec::new();
+    for dir in external {
+        if !folded.iter().any(|parent| dir.starts_with(parent)) {
+            debug!("Found external path dependency {}", dir.display());
+            folded.push(dir);
+        }
+    }
+
+    Ok(folded)
+}
+
+/// Dependency tables directly inside `parent`
+fn dependency_tables(parent: &toml::Table) -> impl Iterator<Item = &toml::Table> {
+    DEPENDENCY_TABLES
+        .iter()
+        .filter_map(move |name| parent.get(*name)?.as_table())
+}
+
+/// Tables nested one level below `key`, e.g. the `[target.<cfg>]` tables
+fn sub_tables<'a>(manifest: &'a toml::Table, key: &str) -> impl Iterator<Item = &'a toml::Table> {
+    manifest
+        .get(key)
+        .and_then(|value| value.as_table())
+        .into_iter()
+        .flat_map(|table| table.values())
+        .filter_map(|value| value.as_table())
+}
+
+/// `path` values of all dependency, patch and replace entries of a manifest
+fn path_dependencies(manifest: &toml::Table) -> Vec<&str> {
+    let mut tables: Vec<&toml::Table> = dependency_tables(manifest).collect();
+    // `[target.<cfg>.dependencies]`
+    tables.extend(sub_tables(manifest, "target").flat_map(dependency_tables));
+    // `[patch.<registry>]`
+    tables.extend(sub_tables(manifest, "patch"));
+    if let Some(workspace) = manifest.get("workspace").and_then(|w| w.as_table()) {
+        tables.extend(dependency_tables(workspace));
+    }
+    if let Some(replace) = manifest.get("replace").and_then(|r| r.as_table()) {
+        tables.push(replace);
+    }
+
+    tables
+        .into_iter()
+        .flat_map(|table| table.values())
+        .filter_map(|dependency| dependency.get("path")?.as_str())
+        .collect()
+}
+
+/// Member directories of the workspace in `dir`, supporting a trailing `*` glob
+fn workspace_members(dir: &Path, manifest: &toml::Table) -> Vec<PathBuf> {
+    let members: Vec<&str> = manifest
+        .get("workspace")
+        .and_then(|workspace| workspace.get("members"))
+        .and_then(|members| members.as_array())
+        .map(|members| members.iter().filter_map(|m| m.as_str()).collect())
+        .unwrap_or_default();
+
+    let mut dirs = Vec::new();
+    for member in members {
+        match member.strip_suffix('*') {
+            Some(prefix) => {
+                let Ok(entries) = fs::read_dir(dir.join(prefix)) else {
+                    continue;
+                };
+                dirs.extend(
+                    entries
+                        .flatten()
+                        .map(|entry| entry.path())
+                        .filter(|path| path.join("Cargo.toml").is_file()),
+                );
+            }
+            None => dirs.push(dir.join(member)),
+        }
+    }
+
+    dirs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::temp_dir;
+
+    /// Write `content` to `path` below `root`, creating its directories
+    fn write(root: &Path, path: &str, content: &str) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    fn package(name: &str) -> String {
+        format!("[package]\nname = \"{}\"\nversion = \"0.1.0\"\n", name)
+    }
+
+    #[test]
+    fn workspace_root_is_found_like_cargo() {
+        let root = temp_dir("manifest-root").canonicalize().unwrap();
+        let ws = root.join("ws");
+        write(
+            &ws,
+            "Cargo.toml",
+            "[workspace]\nmembers = [\"crates/*\"]\nexclude = [\"excluded\"]\n",
+        );
+        write(&ws, "crates/app/Cargo.toml", &package("app"));
+        write(&ws, "crates/app/src/main.rs", "");
+        write(&ws, "excluded/Cargo.toml", &package("excluded"));
+        write(&ws, "nested/Cargo.toml", "[workspace]\n");
+        write(
+            &root,
+            "outside/Cargo.toml",
+            &(package("outside") + "workspace = \"../ws\"\n"),
+        );
+
+        let locate = |dir: &Path, manifest_path: Option<&str>| {
+            locate_workspace_root(dir, manifest_path.map(Path::new)).unwrap()
+        };
+        let results = [
+            // Walking up from a directory of a member
+            locate(&ws.join("crates/app/src"), None),
+            locate(&ws.join("excluded"), None),
+            locate(&ws.join("nested"), None),
+            locate(&root.join("outside"), None),
+            // `--manifest-path` is resolved relative to the current directory
+            locate(&root, Some("ws/crates/app/Cargo.toml")),
+            locate(&ws, Some("excluded/Cargo.toml")),
+        ];
+        let missing = locate_workspace_root(&root, None);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            results,
+            [
+                ws.clone(),
+                ws.join("excluded"),
+                ws.join("nested"),
+                ws.clone(),
+                ws.clone(),
+                ws.join("excluded"),
+            ]
+        );
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn external_path_dependencies_are_collected_from_all_manifests() {
+        let root = temp_dir("manifest-deps").canonicalize().unwrap();
+        write(
+            &root,
+            "project/Cargo.toml",
+            "[workspace]\nmembers = [\"crates/*\"]\n\n\
+             [dependencies]\nshared = { path = \"../shared\" }\n\n\
+             [dev-dependencies]\ngone = { path = \"../gone\" }\n\n\
+             [patch.crates-io]\npatched = { path = \"../patched\" }\n\n\
+             [replace]\n\"replaced:0.1.0\" = { path = \"../replaced\" }\n",
+        );
+        // Only reachable through the `crates/*` glob
+        write(
+            &root,
+            "project/crates/member/Cargo.toml",
+            &(package("member")
+                + "\n[target.'cfg(unix)'.dependencies]\ntool = { path = \"../../../tools/tool\" }\n"),
+        );
+        write(&root, "project/crates/not-a-crate/README", "");
+        write(
+            &root,
+            "shared/Cargo.toml",
+            &(package("shared") + "\n[dependencies]\nnested = { path = \"nested\" }\n"),
+        );
+        write(&root, "shared/nested/Cargo.toml", &package("nested"));
+        write(&root, "patched/Cargo.toml", &package("patched"));
+        write(&root, "replaced/Cargo.toml", &package("replaced"));
+        // The dependency's workspace root comes along for inherited settings
+        write(
+            &root,
+            "tools/Cargo.toml",
+            "[workspace]\nmembers = [\"tool\"]\n",
+        );
+        write(&root, "tools/tool/Cargo.toml", &package("tool"));
+
+        let external = external_path_dependencies(&root.join("project"));
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(
+            external.unwrap(),
+            ["patched", "replaced", "shared", "tools"].map(|dir| root.join(dir))
+        );
+    }
+
+    #[test]
+    fn path_dependencies_inside_the_project_are_not_external() {
+        let root = temp_dir("manifest-internal").canonicalize().unwrap();
+        write(
+            &root,
+            "Cargo.toml",
+            &(package("app") + "\n[dependencies]\nlocal = { path = \"local\" }\n"),
+        );
+        write(&root, "local/Cargo.toml", &package("local"));
+
+        let external = external_path_dependencies(&root);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert!(external.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,298 @@
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+
+/// The parts of `cargo metadata --no-deps` needed to pick targets locally
+#[derive(Debug, Deserialize)]
+pub struct Metadata {
+    pub packages: Vec<Package>,
+    pub workspace_members: Vec<String>,
+    /// Only reported by cargo 1.71 and newer
+    pub workspace_default_members: Option<Vec<String>>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Package {
+    pub id: String,
+    pub name: String,
+    pub manifest_path: PathBuf,
+    pub default_run: Option<String>,
+    pub targets: Vec<Target>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct Target {
+    pub name: String,
+    pub kind: Vec<String>,
+}
+
+impl Metadata {
+    /// Run `cargo metadata` for the project of `manifest` on the local machine
+    pub fn load(manifest: &Path) -> Result<Self> {
+        let output = Command::new("cargo")
+            .args([
+                "metadata",
+                "--no-deps",
+                "--format-version",
+                "1",
+                "--manifest-path",
+            ])
+            .arg(manifest)
+            .output()
+            .context("Executing `cargo metadata` failed")?;
+
+        if !output.status.success() {
+            bail!(
+                "`cargo metadata` failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+
+        serde_json::from_slice(&output.stdout).context("Invalid `cargo metadata` output")
+    }
+
+    /// Packages `cargo run` considers for `manifest`: the `-p` selection, the package of the
+    /// manifest, or the default members of a virtual workspace
+    fn selected_packages(&self, manifest: &Path, package: Option<&str>) -> Result<Vec<&Package>> {
+        if let Some(name) = package {
+            let selected: Vec<&Package> = self.packages.iter().filter(|p| p.name == name).collect();
+            if selected.is_empty() {
+                bail!("package `{}` not found in workspace", name);
+            }
+            return Ok(selected);
+        }
+
+        if let Some(current) = self.packages.iter().find(|p| p.manifest_path == manifest) {
+            return Ok(vec![current]);
+        }
+
+        let members = self
+            .workspace_default_members
+            .as_ref()
+            .unwrap_or(&self.workspace_members);
+        Ok(self
+            .packages
+            .iter()
+            .filter(|p| members.contains(&p.id))
+            .collect())
+    }
+
+    /// Name of the binary `cargo run` would pick for `manifest` without `--bin` or `--example`
+    pub fn default_run_binary(&self, manifest: &Path, package: Option<&str>) -> Result<String> {
+        let packages = self.selected_packages(manifest, package)?;
+
+        if let [package] = packages.as_slice() {
+            if let Some(default_run) = &package.default_run {
+                return Ok(default_run.clone());
+            }
+        }
+
+        let bins: Vec<&str> = packages
+            .iter()
+            .flat_map(|p| &p.targets)
+            .filter(|t| t.kind.iter().any(|k| k == "bin"))
+            .map(|t| t.name.as_str())
+            .collect();
+
+        match bins.as_slice() {
+            [] => bail!("No binaries found to run"),
+            [bin] => Ok(bin.to_string()),
+            _ => bail!(
+                "Could not determine which binary to run. Use the `--bin` option to specify \
+                 a binary, or the `default-run` manifest key.\navailable binaries: {}",
+                bins.join(", ")
+            ),
+        }
+    }
+}
+
+/// Value of `-p`/`--package` in cargo arguments
+pub fn package_arg(args: &[String]) -> Option<String> {
+    let mut iter = args.iter().take_while(|a| *a != "--");
+    while let Some(arg) = iter.next() {
+        if arg == "-p" || arg == "--package" {
+            return iter.next().cloned();
+        }
+        if let Some(package) = arg
+            .strip_prefix("--package=")
+            .or_else(|| arg.strip_prefix("-p").filter(|p| !p.is_empty()))
+        {
+            return Some(package.to_string());
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `cargo metadata` output, trimmed to what is parsed, of a workspace with a root package
+    /// (`app`, two bins and `default-run`) and the members `tool` (one bin), `multi` (two
+    /// bins) and `lib` (no bins)
+    const WORKSPACE: &str = r#"{
+        "packages": [
+            {
+                "id": "path+file:///ws#app@0.1.0",
+                "name": "app",
+                "manifest_path": "/ws/Cargo.toml",
+                "default_run": "server",
+                "targets": [
+                    { "name": "server", "kind": ["bin"] },
+                    { "name": "client", "kind": ["bin"] },
+                    { "name": "demo", "kind": ["example"] }
+                ]
+            },
+            {
+                "id": "path+file:///ws/tool#0.1.0",
+                "name": "tool",
+                "manifest_path": "/ws/tool/Cargo.toml",
+                "default_run": null,
+                "targets": [
+                    { "name": "tool", "kind": ["bin"] },
+                    { "name": "tool", "kind": ["lib"] }
+                ]
+            },
+            {
+                "id": "path+file:///ws/multi#0.1.0",
+                "name": "multi",
+                "manifest_path": "/ws/multi/Cargo.toml",
+                "default_run": null,
+                "targets": [
+                    { "name": "first", "kind": ["bin"] },
+                    { "name": "second", "kind": ["bin"] }
+                ]
+            },
+            {
+                "id": "path+file:///ws/lib#0.1.0",
+                "name": "lib",
+                "manifest_path": "/ws/lib/Cargo.toml",
+                "default_run": null,
+                "targets": [{ "name": "lib", "kind": ["lib"] }]
+            }
+        ],
+        "workspace_members": [
+            "path+file:///ws#app@0.1.0",
+            "path+file:///ws/tool#0.1.0",
+            "path+file:///ws/multi#0.1.0",
+            "path+file:///ws/lib#0.1.0"
+        ],
+        "workspace_default_members": ["path+file:///ws#app@0.1.0"]
+    }"#;
+
+    /// A virtual workspace with the one-bin members `a` and `b`
+    const VIRTUAL: &str = r#"{
+        "packages": [
+            {
+                "id": "path+file:///v/a#0.1.0",
+                "name": "a",
+                "manifest_path": "/v/a/Cargo.toml",
+                "targets": [{ "name": "a", "kind": ["bin"] }]
+            },
+            {
+                "id": "path+file:///v/b#0.1.0",
+                "name": "b",
+                "manifest_path": "/v/b/Cargo.toml",
+                "targets": [{ "name": "b", "kind": ["bin"] }]
+            }
+        ],
+        "workspace_members": ["path+file:///v/a#0.1.0", "path+file:///v/b#0.1.0"]
+    }"#;
+
+    fn metadata(json: &str) -> Metadata {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn default_run(json: &str, manifest: &str, args: &[&str]) -> Result<String> {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        metadata(json).default_run_binary(Path::new(manifest), package_arg(&args).as_deref())
+    }
+
+    #[test]
+    fn honours_default_run() {
+        assert_eq!(
+            default_run(WORKSPACE, "/ws/Cargo.toml", &[]).unwrap(),
+            "server"
+        );
+    }
+
+    #[test]
+    fn picks_the_only_binary() {
+        assert_eq!(
+            default_run(WORKSPACE, "/ws/tool/Cargo.toml", &[]).unwrap(),
+            "tool"
+        );
+    }
+
+    #[test]
+    fn rejects_several_binaries() {
+        let error = default_run(WORKSPACE, "/ws/multi/Cargo.toml", &[]).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("available binaries: first, second"),
+            "{}",
+            error
+        );
+    }
+
+    #[test]
+    fn rejects_packages_without_binaries() {
+        let error = default_run(WORKSPACE, "/ws/lib/Cargo.toml", &[]).unwrap_err();
+        assert_eq!(error.to_string(), "No binaries found to run");
+    }
+
+    #[test]
+    fn selects_package_from_arguments() {
+        // From the workspace root, which is a package of its own
+        for args in [
+            &["-p", "tool"][..],
+            &["--package", "tool"],
+            &["--package=tool"],
+            &["-ptool"],
+        ] {
+            assert_eq!(
+                default_run(WORKSPACE, "/ws/Cargo.toml", args).unwrap(),
+                "tool",
+                "{:?}",
+                args
+            );
+        }
+        assert_eq!(
+            default_run(WORKSPACE, "/ws/tool/Cargo.toml", &["--package=app"]).unwrap(),
+            "server"
+        );
+        assert!(default_run(WORKSPACE, "/ws/Cargo.toml", &["-p", "missing"]).is_err());
+    }
+
+    #[test]
+    fn virtual_workspace_uses_members() {
+        let error = default_run(VIRTUAL, "/v/Cargo.toml", &[]).unwrap_err();
+        assert!(error.to_string().contains("available binaries: a, b"));
+        assert_eq!(
+            default_run(VIRTUAL, "/v/Cargo.toml", &["-p", "b"]).unwrap(),
+            "b"
+        );
+    }
+
+    #[test]
+    fn default_members_narrow_the_selection() {
+        // Neither the root nor a member, e.g. a manifest outside of the workspace
+        assert_eq!(
+            default_run(WORKSPACE, "/elsewhere/Cargo.toml", &[]).unwrap(),
+            "server"
+        );
+    }
+
+    #[test]
+    fn package_arg_stops_at_separator() {
+        let args: Vec<String> = ["--bin", "x", "--", "-p", "other"]
+            .iter()
+            .map(|a| a.to_string())
+            .collect();
+        assert_eq!(package_arg(&args), None);
+    }
+}
@@ -0,0 +1,521 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::{Mutex, OnceLock};
+use std::time::Duration;
+
+use log::{debug, info};
+use ssh2::{Channel, CheckResult, ErrorCode, KnownHostFileKind, Session};
+
+use crate::config::HostConfig;
+use crate::error::OffloadError;
+use crate::transport::{ExecOptions, Forward, RemoteChild, RsyncRemote, Transport};
+use crate::util::local_user_name;
+
+/// libssh2's `LIBSSH2_ERROR_EAGAIN`, returned by every call that would block
+const ERROR_EAGAIN: i32 = -37;
+
+/// Pause of the channel loop when no data moved in either direction
+const IDLE_POLL: Duration = Duration::from_millis(2);
+
+/// Keys tried when no identity file is configured and the agent has none that works
+const DEFAULT_IDENTITIES: &[&str] = &["id_ed25519", "id_ecdsa", "id_rsa"];
+
+/// SSH client running in-process: one authenticated session per invocation, every
+/// command gets its own channel
+pub struct NativeTransport {
+    session: Session,
+    name: String,
+}
+
+fn transport_error(context: &str, e: impl std::fmt::Display) -> OffloadError {
+    OffloadError::Transport(format!("{}: {}", context, e))
+}
+
+fn would_block(e: &ssh2::Error) -> bool {
+    e.code() == ErrorCode::Session(ERROR_EAGAIN)
+}
+
+/// Repeat a libssh2 call on the non-blocking session until it doesn't need to wait
+fn retry<T>(mut call: impl FnMut() -> Result<T, ssh2::Error>) -> Result<T, ssh2::Error> {
+    loop {
+        match call() {
+            Err(e) if would_block(&e) => std::thread::sleep(IDLE_POLL),
+            result => return result,
+        }
+    }
+}
+
+fn ssh_dir() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".ssh"))
+}
+
+impl NativeTransport {
+    pub fn connect(
+        host: &HostConfig,
+        connect_timeout: Option<Duration>,
+    ) -> Result<Self, OffloadError> {
+        let name = host.display_name();
+        let (user, hostname) = match host.address.split_once('@') {
+            Some((user, hostname)) => (user.to_string(), hostname.to_string()),
+            None => (local_user_name(), host.address.clone()),
+        };
+
+        let addr = (hostname.as_str(), host.port)
+            .to_socket_addrs()
+            .map_err(|e| transport_error(&format!("cannot resolve {}", hostname), e))?
+            .next()
+            .ok_or_else(|| OffloadError::Transport(format!("cannot resolve {}", hostname)))?;
+        let tcp = match connect_timeout {
+            Some(timeout) => TcpStream::connect_timeout(&addr, timeout),
+            None => TcpStream::connect(addr),
+        }
+        .map_err(|e| transport_error(&format!("cannot connect to {}", name), e))?;
+
+        let mut session = Session::new().map_err(|e| transport_error("ssh", e))?;
+        session.set_tcp_stream(tcp);
+        session
+            .handshake()
+            .map_err(|e| transport_error(&format!("handshake with {} failed", name), e))?;
+
+        verify_host_key(&session, &hostname, host.port)?;
+        authenticate(&session, &user, host.identity_file.as_deref())?;
+        debug!("Authenticated to {} as {}", name, user);
+
+        session.set_keepalive(false, 30);
+        session.set_blocking(false);
+        Ok(NativeTransport { session, name })
+    }
+}
+
+/// Check the host key against `~/.ssh/known_hosts`. Unknown hosts are rejected instead of
+/// prompting, so the key has to be accepted once with `ssh` or added with `ssh-keyscan`.
+fn verify_host_key(session: &Session, hostname: &str, port: u16) -> Result<(), OffloadError> {
+    let (key, _) = session
+        .host_key()
+        .ok_or_else(|| OffloadError::Transport("server sent no host key".to_string()))?;
+
+    let mut known_hosts = session
+        .known_hosts()
+        .map_err(|e| transport_error("cannot read known hosts", e))?;
+    let known_hosts_file = ssh_dir().map(|dir| dir.join("known_hosts"));
+    if let Some(file) = known_hosts_file.as_deref().filter(|file| file.exists()) {
+        known_hosts
+            .read_file(file, KnownHostFileKind::OpenSSH)
+            .map_err(|e| transport_error(&format!("cannot read {}", file.display()), e))?;
+    }
+
+    match known_hosts.check_port(hostname, port, key) {
+        CheckResult::Match => Ok(()),
+        CheckResult::NotFound => Err(OffloadError::Transport(format!(
+            "host key of {} is not in ~/.ssh/known_hosts; connect once with ssh or add it \
+             with ssh-keyscan",
+            hostname
+        ))),
+        CheckResult::Mismatch => Err(OffloadError::Transport(format!(
+            "HOST KEY OF {} DOES NOT MATCH ~/.ssh/known_hosts, refusing to connect",
+            hostname
+        ))),
+        CheckResult::Failure => Err(OffloadError::Transport(format!(
+            "cannot check the host key of {}",
+            hostname
+        ))),
+    }
+}
+
+/// Authenticate with the configured identity file, or with the agent and the default keys
+fn authenticate(
+    session: &Session,
+    user: &str,
+    identity_file: Option<&Path>,
+) -> Result<(), OffloadError> {
+    if let Some(identity_file) = identity_file {
+        return session
+            .userauth_pubkey_file(user, None, identity_file, None)
+            .map_err(|e| {
+                transport_error(
+                    &format!("authentication with {} failed", identity_file.display()),
+                    e,
+                )
+            });
+    }
+
+    match session.userauth_agent(user) {
+        Ok(()) => return Ok(()),
+        Err(e) => debug!("Agent authentication failed: {}", e),
+    }
+
+    let identities = ssh_dir()
+        .into_iter()
+        .flat_map(|dir| DEFAULT_IDENTITIES.iter().map(move |name| dir.join(name)))
+        .filter(|path| path.exists());
+    for identity in identities {
+        match session.userauth_pubkey_file(user, None, &identity, None) {
+            Ok(()) => return Ok(()),
+            Err(e) => debug!("Authentication with {} failed: {}", identity.display(), e),
+        }
+    }
+
+    Err(OffloadError::Transport(format!(
+        "authentication as {} failed (tried the ssh agent and unencrypted keys in ~/.ssh)",
+        user
+    )))
+}
+
+impl Transport for NativeTransport {
+    fn spawn(&self, command: &str, options: &ExecOptions) -> Result<RemoteChild, OffloadError> {
+        let open_error = |e| transport_error(&format!("cannot run a command on {}", self.name), e);
+        let mut channel = retry(|| self.session.channel_session()).map_err(open_error)?;
+        if options.tty {
+            let columns = std::env::var("COLUMNS")
+                .ok()
+                .and_then(|columns| columns.parse().ok())
+                .unwrap_or(80);
+            retry(|| channel.request_pty("xterm", None, Some((columns, 24, 0, 0))))
+                .map_err(open_error)?;
+        }
+        retry(|| channel.exec(command)).map_err(open_error)?;
+
+        let mut listeners = Vec::new();
+        for forward in options.forwards {
+            let listener = TcpListener::bind(("127.0.0.1", forward.local))
+                .and_then(|listener| listener.set_nonblocking(true).map(|_| listener))
+                .map_err(|e| transport_error(&format!("cannot listen on {}", forward.local), e))?;
+            info!("Port forwarding: {}:{}", forward.local, forward.remote);
+            listeners.push((listener, *forward));
+        }
+
+        let (stdin_tx, stdin_rx) = mpsc::channel();
+        let pipe_error = |e| transport_error("cannot create pipe", e);
+        let (stdout_reader, stdout_writer) = io::pipe().map_err(pipe_error)?;
+        let (stderr_reader, stderr_writer) = io::pipe().map_err(pipe_error)?;
+
+        let (stdin, input): (Option<Box<dyn Write + Send>>, _) = if options.interactive {
+            (None, Input::Terminal)
+        } else {
+            (
+                Some(Box::new(ChannelStdin(stdin_tx))),
+                Input::Pipe(stdin_rx),
+            )
+        };
+
+        let session = self.session.clone();
+        let pump = std::thread::spawn(move || {
+            let mut pump = Pump {
+                session,
+                channel,
+                stdin: input,
+                stdout: Some(stdout_writer),
+                stderr: Some(stderr_writer),
+                listeners,
+                tunnels: Vec::new(),
+            };
+            pump.run()
+        });
+
+        let name = self.name.clone();
+        Ok(RemoteChild::new(
+            stdin,
+            Box::new(stdout_reader),
+            Box::new(stderr_reader),
+            move || {
+                pump.join()
+                    .unwrap_or_else(|_| Err(io::Error::other("channel thread panicked")))
+                    .map_err(|e| transport_error(&format!("connection to {} failed", name), e))
+            },
+        ))
+    }
+
+    fn rsync_remote(&self) -> Option<RsyncRemote> {
+        None
+    }
+}
+
+/// Stdin of a remote command, handed to the channel loop
+struct ChannelStdin(Sender<Vec<u8>>);
+
+impl Write for ChannelStdin {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Where the channel loop takes a command's input from
+enum Input {
+    /// Written through the command's `ChannelStdin`
+    Pipe(Receiver<Vec<u8>>),
+    /// Typed into our own terminal
+    Terminal,
+}
+
+impl Input {
+    fn try_recv(&self) -> Result<Vec<u8>, TryRecvError> {
+        match self {
+            Input::Pipe(receiver) => receiver.try_recv(),
+            Input::Terminal => terminal_input()
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .try_recv(),
+        }
+    }
+}
+
+/// Our terminal's input, read by one thread for the whole process. Reading only ends with
+/// the input itself, so the thread outlives the commands; input typed between two
+/// interactive commands is kept for the next one.
+fn terminal_input() -> &'static Mutex<Receiver<Vec<u8>>> {
+    static TERMINAL: OnceLock<Mutex<Receiver<Vec<u8>>>> = OnceLock::new();
+    TERMINAL.get_or_init(|| {
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let mut terminal = io::stdin();
+            while let Ok(n @ 1..) = terminal.read(&mut buf) {
+                if sender.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+        Mutex::new(receiver)
+    })
+}
+
+/// A forwarded connection between a local socket and a `direct-tcpip` channel
+struct Tunnel {
+    stream: TcpStream,
+    channel: Channel,
+    to_remote: Vec<u8>,
+    to_local: Vec<u8>,
+    local_eof: bool,
+    remote_eof: bool,
+    eof_sent: bool,
+}
+
+/// Moves data between the channels of a command and local pipes and sockets. libssh2
+/// sessions cannot be read from several threads at once, so one loop serves them all.
+struct Pump {
+    session: Session,
+    channel: Channel,
+    stdin: Input,
+    stdout: Option<io::PipeWriter>,
+    stderr: Option<io::PipeWriter>,
+    listeners: Vec<(TcpListener, Forward)>,
+    tunnels: Vec<Tunnel>,
+}
+
+impl Pump {
+    /// Run until the command's output ends and return its exit code
+    fn run(&mut self) -> io::Result<i32> {
+        let mut pending = Vec::new();
+        let mut stdin_open = true;
+        let mut buf = vec![0u8; 32 * 1024];
+
+        while self.stdout.is_some() || self.stderr.is_some() {
+            let mut busy = false;
+
+            if pending.is_empty() && stdin_open {
+                match self.stdin.try_recv() {
+                    Ok(data) => pending = data,
+                    Err(TryRecvError::Empty) => {}
+                    Err(TryRecvError::Disconnected) => {
+                        if send_eof(&mut self.channel)? {
+                            stdin_open = false;
+                        }
+                    }
+                }
+            }
+            if !pending.is_empty() {
+                match self.channel.write(&pending) {
+                    Ok(n) => {
+                        pending.drain(..n);
+                        busy = true;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    // The command doesn't read its input (anymore)
+                    Err(_) => {
+                        pending.clear();
+                        stdin_open = false;
+                    }
+                }
+            }
+
+            let mut stdout = self.channel.stream(0);
+            busy |= forward_output(&mut stdout, &mut self.stdout, &mut buf)?;
+            let mut stderr = self.channel.stderr();
+            busy |= forward_output(&mut stderr, &mut self.stderr, &mut buf)?;
+
+            busy |= self.serve_forwards(&mut buf)?;
+
+            if !busy {
+                std::thread::sleep(IDLE_POLL);
+            }
+        }
+
+        retry(|| self.channel.wait_close()).map_err(io::Error::from)?;
+        let code = self.channel.exit_status().map_err(io::Error::from)?;
+        if let Ok(signal) = self.channel.exit_signal() {
+            if let Some(signal) = signal.exit_signal {
+                return Err(io::Error::other(format!("command killed by SIG{}", signal)));
+            }
+        }
+
+        Ok(code)
+    }
+
+    fn serve_forwards(&mut self, buf: &mut [u8]) -> io::Result<bool> {
+        let mut busy = false;
+
+        for (listener, forward) in &self.listeners {
+            match listener.accept() {
+                Ok((stream, peer)) => {
+                    debug!("Forwarding {} to remote port {}", peer, forward.remote);
+                    stream.set_nonblocking(true)?;
+                    let channel = retry(|| {
+                        self.session.channel_direct_tcpip(
+                            "localhost",
+                            forward.remote,
+                            Some((&peer.ip().to_string(), peer.port())),
+                        )
+                    })
+                    .map_err(io::Error::from)?;
+                    self.tunnels.push(Tunnel {
+                        stream,
+                        channel,
+                        to_remote: Vec::new(),
+                        to_local: Vec::new(),
+                        local_eof: false,
+                        remote_eof: false,
+                        eof_sent: false,
+                    });
+                    busy = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(e) => return Err(e),
+            }
+        }
+
+        for tunnel in &mut self.tunnels {
+            busy |= tunnel.pump(buf);
+        }
+        self.tunnels.retain(|tunnel| {
+            !(tunnel.local_eof
+                && tunnel.remote_eof
+                && tunnel.to_remote.is_empty()
+                && tunnel.to_local.is_empty())
+        });
+
+        Ok(busy)
+    }
+}
+
+/// Send EOF on the channel, `false` if it has to be tried again
+fn send_eof(channel: &mut Channel) -> io::Result<bool> {
+    match channel.send_eof() {
+        Ok(()) => Ok(true),
+        Err(e) if would_block(&e) => Ok(false),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Copy what is available on a channel stream to `out`, which is dropped at the end of the
+/// stream. A reader that went away doesn't stop the stream from being drained.
+fn forward_output(
+    stream: &mut impl Read,
+    out: &mut Option<io::PipeWriter>,
+    buf: &mut [u8],
+) -> io::Result<bool> {
+    let Some(writer) = out else {
+        return Ok(false);
+    };
+
+    match stream.read(buf) {
+        Ok(0) => {
+            *out = None;
+            Ok(true)
+        }
+        Ok(n) => {
+            if writer.write_all(&buf[..n]).is_err() {
+                debug!("Remote output is not read anymore");
+            }
+            Ok(true)
+        }
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+impl Tunnel {
+    /// Move data in both directions, returns whether anything happened
+    fn pump(&mut self, buf: &mut [u8]) -> bool {
+        let mut busy = false;
+
+        if self.to_remote.is_empty() && !self.local_eof {
+            match self.stream.read(buf) {
+                Ok(0) => {
+                    self.local_eof = true;
+                    busy = true;
+                }
+                Ok(n) => {
+                    self.to_remote.extend_from_slice(&buf[..n]);
+                    busy = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => self.local_eof = true,
+            }
+        }
+        if !self.to_remote.is_empty() {
+            match self.channel.write(&self.to_remote) {
+                Ok(n) => {
+                    self.to_remote.drain(..n);
+                    busy = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => {
+                    self.to_remote.clear();
+                    self.remote_eof = true;
+                }
+            }
+        } else if self.local_eof && !self.eof_sent {
+            self.eof_sent = send_eof(&mut self.channel).unwrap_or(true);
+        }
+
+        if self.to_local.is_empty() && !self.remote_eof {
+            match self.channel.read(buf) {
+                Ok(0) => {
+                    self.remote_eof = true;
+                    let _ = self.stream.shutdown(Shutdown::Write);
+                    busy = true;
+                }
+                Ok(n) => {
+                    self.to_local.extend_from_slice(&buf[..n]);
+                    busy = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => self.remote_eof = true,
+            }
+        }
+        if !self.to_local.is_empty() {
+            match self.stream.write(&self.to_local) {
+                Ok(n) => {
+                    self.to_local.drain(..n);
+                    busy = true;
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                Err(_) => {
+                    self.to_local.clear();
+                    self.local_eof = true;
+                }
+            }
+        }
+
+        busy
+    }
+}
@@ -1,131 +1,956 @@
-use anyhow::Context;
-use log::{debug, info};
-use std::io::Write;
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
+use std::process::Command;
+use std::time::{Duration, Instant};
 use std::{fs, io};
 
+use crate::artifact::{with_artifact_message_format, Artifact, ArtifactCollector};
+use crate::cargo_config;
+use crate::config::{Config, HostConfig, SyncMethod};
+use crate::container::Container;
+use crate::delta::{self, Plan};
+use crate::error::OffloadError;
+use crate::git::GitRepo;
+use crate::manifest::{external_path_dependencies, locate_workspace_root};
+use crate::pool::{self, select_host, DEFAULT_MIN_FREE_MB};
+use crate::rewrite::PathRewriter;
+use crate::sccache::{self, Sccache, Stats};
+use crate::sync_filter;
+use crate::transfer::{self, Change, Selection, SyncRequest};
+use crate::transport::{self, ExecOptions, Forward, Transport};
 use crate::util::*;
 use crate::Cli;
 
+/// Seconds to wait for the remote lock unless configured otherwise
+const DEFAULT_LOCK_TIMEOUT: u64 = 600;
+
+/// Seconds between attempts to acquire the remote lock
+const LOCK_POLL_INTERVAL: u64 = 2;
+
 pub struct CargoOffload {
-    host: String,
-    port: u16,
+    host: HostConfig,
+    transport: Box<dyn Transport>,
+    local_dir: PathBuf,
     remote_dir: String,
+    /// Common ancestor of the project and its external path dependencies, mirrored to `remote_root`
+    local_root: PathBuf,
+    remote_root: String,
+    /// Path dependencies outside of the project, synced next to it
+    path_dependencies: Vec<PathBuf>,
+    /// Repository to sync with git instead of rsync (`sync.method = "git"`)
+    git_repo: Option<GitRepo>,
+    /// Sync with the builtin delta protocol and copy artifacts with tar, either configured
+    /// (`sync.method = "builtin"`) or because rsync cannot run over the transport
+    builtin_sync: bool,
+    /// Where cargo runs on the remote: the directory matching the local working directory
+    remote_work_dir: String,
+    /// Container on the host that cargo and rustup run in (`[container]`)
+    container: Option<Container>,
+    /// Compile through sccache with a cache shared by the host's projects (`[sccache]`)
+    sccache: Option<Sccache>,
     toolchain: Option<String>,
     target: String,
+    /// The synced cargo config sets `build.target`, which cargo picks up by itself
+    config_build_target: bool,
+    env_vars: Vec<String>,
+    forward_ports: Vec<Forward>,
+    sync_excludes: Vec<String>,
+    sync_gitignore: bool,
+    /// The project's cargo config to sync, `None` if there is none or syncing it is disabled
+    cargo_config: Option<PathBuf>,
+    cargo_config_deny: Vec<String>,
     copy_all_artifacts: bool,
+    command_artifacts: BTreeMap<String, Vec<String>>,
+    remap_path_prefix: bool,
+    lock_timeout: Duration,
     progress_flag: String,
+    /// Keep stdout clean for machine readable cargo output (`--message-format=json`)
+    quiet: bool,
+}
+
+/// Remote lock on the project directory, released when dropped
+pub struct RemoteLock<'a> {
+    offload: &'a CargoOffload,
+    owner: String,
+}
+
+impl Drop for RemoteLock<'_> {
+    fn drop(&mut self) {
+        let lock_dir = self.offload.lock_dir();
+        // The owner file is `pid=<pid> host=<host> since=<time>`, the first two fields
+        // have to match exactly so `pid=23` isn't mistaken for `pid=123`
+        let release_cmd = format!(
+            "[ \"$(cut -d ' ' -f 1,2 {lock}/owner 2>/dev/null)\" = {owner} ] && rm -rf {lock}",
+            owner = shell_quote(&self.owner),
+            lock = shell_quote(&lock_dir),
+        );
+
+        match self.offload.run_ssh_output(&release_cmd) {
+            Ok(_) => debug!("Released remote lock {}", lock_dir),
+            Err(e) => warn!("Failed to release remote lock {}: {}", lock_dir, e),
+        }
+    }
 }
 
 impl CargoOffload {
+    /// Set up offloading for the project in the current directory. With a host pool, the
+    /// least busy host is picked, or `host` if given.
     pub fn new(
         cli: &Cli,
         toolchain: Option<String>,
         progress_flag: String,
+        host: Option<HostConfig>,
     ) -> Result<Self, Box<dyn std::error::Error>> {
-        // Parse host and port from environment variable or CLI args
-        let (host, port) = Self::parse_host_and_port(cli)?;
-        info!("Executing command on {}:{}", host, port);
-
-        // Get current folder name
         let current_dir = std::env::current_dir()?;
-        let local_folder_name = current_dir
-            .file_name()
-            .ok_or("Cannot determine current folder name")?
-            .to_string_lossy()
-            .to_string();
 
-        let remote_dir = format!("/tmp/cargo-offload/{}", local_folder_name);
+        // Everything is synced from the workspace root, so inherited settings and
+        // sibling crates are available when working in a member's directory
+        let manifest_path = match cli.command.cargo_args() {
+            Some(args) => {
+                let args: Vec<String> = args.iter().take_while(|a| *a != "--").cloned().collect();
+                parse_flag(&args, "manifest-path")?
+            }
+            None => None,
+        };
+        let project_root =
+            locate_workspace_root(&current_dir, manifest_path.as_deref().map(Path::new))?;
+        debug!("Project root: {}", project_root.display());
+
+        // Merge project config, user config, environment and CLI arguments
+        let mut config = Config::load(&project_root, cli)?;
+        config.prepare_ssh_multiplex();
 
-        let target = cli
-            .target
-            .clone()
-            .unwrap_or_else(|| "x86_64-unknown-linux-gnu".to_string());
+        let host = match host {
+            Some(host) => host,
+            None if config.pool.is_empty() => config.selected_host()?,
+            None => {
+                // Commands that don't build act on the state the last build left behind
+                let candidates = config.pool_hosts()?;
+                let last = pool::last_host(&project_root, &candidates);
+                match last.filter(|_| cli.command.uses_last_build_host()) {
+                    Some(host) => {
+                        debug!("Using {} like the last build", host.display_name());
+                        host
+                    }
+                    None => {
+                        let host = select_host(
+                            candidates,
+                            config.pool_min_free_mb.unwrap_or(DEFAULT_MIN_FREE_MB),
+                        )?;
+                        pool::record_host(&project_root, &host);
+                        host
+                    }
+                }
+            }
+        };
+        info!("Executing command on {}", host.display_name());
+        let transport = transport::connect(&host, None)?;
+        let builtin_sync =
+            config.sync.method == Some(SyncMethod::Builtin) || transport.rsync_remote().is_none();
+        let forward_ports = config
+            .forward
+            .iter()
+            .map(|spec| Forward::parse(spec))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let remote_root = format!(
+            "{}/{}",
+            host.remote_base_dir,
+            remote_project_path(
+                &project_root,
+                config.git_worktree_remote_dir.unwrap_or(false)
+            )?
+        );
+
+        // Path dependencies outside of the project are mirrored with the same relative
+        // layout below the remote directory, so `../shared` still resolves there
+        let path_dependencies = external_path_dependencies(&project_root)?;
+
+        // The git checkout covers the whole repository, which may start above the project
+        let git_repo = match config.sync.method {
+            Some(SyncMethod::Git) => {
+                let repo = GitRepo::discover(&project_root);
+                if repo.is_none() {
+                    warn!("Project is not in a git repository, syncing with rsync");
+                }
+                repo
+            }
+            Some(SyncMethod::Rsync | SyncMethod::Builtin) | None => None,
+        };
+
+        let mut local_root = project_root.clone();
+        let roots = path_dependencies
+            .iter()
+            .chain(git_repo.as_ref().map(|repo| &repo.toplevel));
+        for dependency in roots {
+            while !dependency.starts_with(&local_root) {
+                local_root = local_root
+                    .parent()
+                    .ok_or("Path dependencies have no common ancestor")?
+                    .to_path_buf();
+            }
+        }
+        let remote_dir = remote_path(&remote_root, &local_root, &project_root);
 
         // Use provided toolchain, detect it from `cargo --version` or use toolchain files
         let final_toolchain = toolchain
-            .or_else(|| detect_toolchain_from_files().unwrap_or(None))
+            .or_else(|| detect_toolchain_from_files(&project_root).unwrap_or(None))
             .or_else(|| detect_toolchain_from_cargo().unwrap_or(None));
 
+        // Run cargo in the same member directory on the remote, if we are inside the workspace
+        let current_dir = current_dir.canonicalize()?;
+        let remote_work_dir = if current_dir.starts_with(&project_root) {
+            remote_path(&remote_dir, &project_root, &current_dir)
+        } else {
+            remote_dir.clone()
+        };
+
+        let cargo_config =
+            cargo_config::find(&project_root).filter(|_| config.sync.cargo_config.unwrap_or(true));
+        let cargo_config_deny: Vec<String> = cargo_config::DEFAULT_DENY
+            .iter()
+            .map(|key| key.to_string())
+            .chain(config.sync.cargo_config_deny)
+            .collect();
+
+        // A `build.target` in the synced cargo config is what the project builds for, it
+        // takes precedence over our default. Errors are reported when syncing the config.
+        let synced_config = cargo_config
+            .as_deref()
+            .and_then(|path| cargo_config::load_filtered(path, &cargo_config_deny).ok())
+            .map(|(config, _)| config);
+        let config_build_target = synced_config
+            .as_ref()
+            .is_some_and(cargo_config::has_build_target);
+        let target = synced_config
+            .as_ref()
+            .and_then(cargo_config::build_target)
+            .map(str::to_string)
+            .or(config.target)
+            .or_else(|| host.target.clone())
+            .unwrap_or_else(|| "x86_64-unknown-linux-gnu".to_string());
+        let sccache = Sccache::new(config.sccache, &host, &remote_root);
+        let shared_dirs = sccache
+            .iter()
+            .map(|sccache| sccache.dir.clone())
+            .chain(host.cargo_home.clone())
+            .collect();
+        let container = Container::new(config.container, &remote_root, shared_dirs);
+
         Ok(CargoOffload {
             host,
-            port,
+            transport,
+            local_dir: project_root,
             remote_dir,
+            local_root,
+            remote_root,
+            path_dependencies,
+            git_repo,
+            builtin_sync,
+            remote_work_dir,
+            container,
+            sccache,
             toolchain: final_toolchain,
             target,
-            copy_all_artifacts: cli.copy_all_artifacts,
+            env_vars: config.env,
+            forward_ports,
+            sync_excludes: config.sync.exclude,
+            sync_gitignore: config.sync.gitignore.unwrap_or(true),
+            cargo_config,
+            cargo_config_deny,
+            config_build_target,
+            copy_all_artifacts: config.artifacts.copy_all.unwrap_or(false),
+            command_artifacts: config
+                .commands
+                .into_iter()
+                .map(|(name, command)| (name, command.artifacts))
+                .collect(),
+            remap_path_prefix: config.remap_path_prefix.unwrap_or(true),
+            lock_timeout: Duration::from_secs(config.lock_timeout.unwrap_or(DEFAULT_LOCK_TIMEOUT)),
             progress_flag,
+            quiet: cli
+                .command
+                .cargo_args()
+                .is_some_and(uses_json_message_format),
         })
     }
 
-    fn parse_host_and_port(cli: &Cli) -> Result<(String, u16), Box<dyn std::error::Error>> {
-        let host_str = cli
-            .host
-            .clone()
-            .or_else(|| std::env::var("CARGO_OFFLOAD_HOST").ok())
-            .ok_or("Host must be specified via --host or CARGO_OFFLOAD_HOST env var")?;
+    pub fn forward_ports(&self) -> &[Forward] {
+        &self.forward_ports
+    }
+
+    /// Rewrites remote project paths in command output to local ones. Inside JSON
+    /// messages the local path has to be escaped like any other JSON string content.
+    fn path_rewriter(&self, json: bool) -> PathRewriter {
+        let local_dir = self.local_root.to_string_lossy().to_string();
+        let local_dir = if json {
+            json_escape(&local_dir)
+        } else {
+            local_dir
+        };
+
+        PathRewriter::new(vec![(self.remote_root.clone(), local_dir)])
+    }
+
+    /// rsync's progress option, `None` keeps stdout for cargo's messages in quiet mode
+    fn progress(&self) -> Option<&str> {
+        (!self.quiet).then_some(self.progress_flag.as_str())
+    }
+
+    /// Lock directory next to the remote project directory, so `rsync --delete` leaves it alone
+    fn lock_dir(&self) -> String {
+        format!("{}.lock", self.remote_root)
+    }
+
+    /// Acquire the remote project lock, waiting for other builds of the same project to finish
+    pub fn lock(&self) -> Result<RemoteLock<'_>, Box<dyn std::error::Error>> {
+        let lock_dir = self.lock_dir();
+        let owner = format!(
+            "pid={} host={}",
+            std::process::id(),
+            sanitize_path_component(&local_host_name())
+        );
 
-        // Parse format: user@host:port or host:port or just host
-        if let Some(colon_pos) = host_str.rfind(':') {
-            let (host_part, port_part) = host_str.split_at(colon_pos);
-            let port_str = &port_part[1..]; // Remove the ':'
+        // `mkdir` is atomic, so only one invocation can create the lock directory. Only an
+        // existing lock directory means another build holds the lock, other failures such as
+        // missing permissions are reported right away.
+        let acquire_cmd = format!(
+            "mkdir -p \"$(dirname {lock})\" || exit 1; \
+             if error=$(mkdir {lock} 2>&1); then \
+             echo \"{owner} since=$(date -u +%Y-%m-%dT%H:%M:%SZ)\" > {lock}/owner && echo acquired; \
+             elif [ -d {lock} ]; then cat {lock}/owner 2>/dev/null || echo unknown; \
+             else echo \"$error\" >&2; exit 1; fi",
+            lock = shell_quote(&lock_dir),
+            owner = owner,
+        );
 
-            if let Ok(port) = port_str.parse::<u16>() {
-                let final_port = cli.port.unwrap_or(port);
-                return Ok((host_part.to_string(), final_port));
+        let start = Instant::now();
+        let mut last_holder = String::new();
+        loop {
+            let output = self.transport.output(&acquire_cmd, &[])?;
+            if output.code != 0 {
+                return Err(OffloadError::Lock(format!(
+                    "Cannot create lock {} on {}: {}",
+                    lock_dir,
+                    self.host.display_name(),
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ))
+                .into());
+            }
+            let output = String::from_utf8_lossy(&output.stdout);
+            let holder = output.trim();
+            if holder == "acquired" {
+                debug!("Acquired remote lock {}", lock_dir);
+                return Ok(RemoteLock {
+                    offload: self,
+                    owner,
+                });
+            }
+
+            let field = |key: &str| {
+                holder
+                    .split_whitespace()
+                    .find_map(|part| part.strip_prefix(key)?.strip_prefix('='))
+                    .unwrap_or("?")
+                    .to_string()
+            };
+            let (pid, host, since) = (field("pid"), field("host"), field("since"));
+
+            // A lock left behind by a crashed invocation on this machine can be removed right away
+            let local_host = sanitize_path_component(&local_host_name());
+            if let Ok(pid) = pid.parse::<u32>() {
+                if host == local_host && !process_is_running(pid) {
+                    warn!(
+                        "Removing stale lock held by pid {} on host {} since {}",
+                        pid, host, since
+                    );
+                    self.run_ssh_output(&format!("rm -rf {}", shell_quote(&lock_dir)))?;
+                    continue;
+                }
+            }
+
+            if start.elapsed() >= self.lock_timeout {
+                return Err(OffloadError::Lock(format!(
+                    "Timed out waiting for lock held by pid {} on host {} since {} \
+                     (remove {} on {} if it is stale)",
+                    pid,
+                    host,
+                    since,
+                    lock_dir,
+                    self.host.display_name()
+                ))
+                .into());
             }
-        }
 
-        // No port in host string, use CLI arg or default
-        let port = cli.port.unwrap_or(22);
-        Ok((host_str, port))
+            if holder != last_holder {
+                warn!(
+                    "Waiting for lock held by pid {} on host {} since {}...",
+                    pid, host, since
+                );
+                last_holder = holder.to_string();
+            }
+
+            std::thread::sleep(Duration::from_secs(LOCK_POLL_INTERVAL));
+        }
     }
 
     pub fn sync_source(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("Syncing source code to remote...");
 
-        // Create remote directory if it doesn't exist
-        self.run_ssh_command(&format!("mkdir -p {}", self.remote_dir), false, &[])?;
-
-        // Use rsync to sync source, excluding target directory and other build artifacts
-        let mut rsync_cmd = Command::new("rsync");
-        rsync_cmd
-            .arg("-a")
-            .arg("--delete")
-            .arg("--compress")
-            .arg("-e")
-            .arg(format!("ssh -p {}", self.port))
-            .arg(&self.progress_flag)
-            .arg("--exclude=target/")
-            .arg("--exclude=.git/")
-            .arg("--exclude=*.swp")
-            .arg("--exclude=*.tmp")
-            .arg("--exclude=.cargo/")
-            .arg(".")
-            .arg(format!("{}:{}/", self.host, self.remote_dir))
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit());
-
-        let output = rsync_cmd.output().context("rsync failed")?;
-        if !output.status.success() {
-            return Err(
-                format!("rsync failed: {}", String::from_utf8_lossy(&output.stderr)).into(),
+        let dirs = self.sync_dirs();
+
+        // Create remote directories if they don't exist
+        let remote_dirs: Vec<&str> = dirs.iter().map(|(_, remote)| remote.as_str()).collect();
+        self.run_ssh_command(
+            &format!("mkdir -p {}", shell_join(&remote_dirs)),
+            false,
+            &[],
+        )
+        .map_err(step_error(
+            OffloadError::Sync,
+            "cannot create the remote directories",
+        ))?;
+
+        // Directories inside the repository come with the git checkout
+        let git_toplevel = match &self.git_repo {
+            Some(repo) if self.sync_git(repo)? => Some(repo.toplevel.as_path()),
+            _ => None,
+        };
+
+        let dirs: Vec<(&Path, String)> = dirs
+            .into_iter()
+            .filter(|(local, _)| !git_toplevel.is_some_and(|toplevel| local.starts_with(toplevel)))
+            .collect();
+
+        if self.builtin_sync {
+            let locals: Vec<&Path> = dirs.iter().map(|(local, _)| *local).collect();
+            if !locals.is_empty() {
+                self.sync_builtin(&locals)?;
+            }
+            return self.sync_cargo_config();
+        }
+
+        for (local, remote) in dirs {
+            debug!("Syncing {} to {}", local.display(), remote);
+            self.sync_dir(local, &remote)?;
+        }
+
+        self.sync_cargo_config()
+    }
+
+    /// Bring the remote checkout to the local `HEAD`, sending only the missing history, then
+    /// apply the local changes and untracked files on top. Returns `false` if the remote
+    /// checkout cannot be updated incrementally and rsync has to be used instead.
+    fn sync_git(&self, repo: &GitRepo) -> Result<bool, Box<dyn std::error::Error>> {
+        let sync_error = |e: anyhow::Error| OffloadError::Sync(format!("{:#}", e));
+
+        let base = match repo.head() {
+            Ok(base) => base,
+            Err(e) => {
+                warn!("Cannot sync with git, falling back to rsync: {:#}", e);
+                return Ok(false);
+            }
+        };
+        let diff = repo.diff(&base).map_err(sync_error)?;
+        let untracked = repo.untracked_files().map_err(sync_error)?;
+        let state = format!("{:016x}", repo.sync_state(&base, &diff, &untracked));
+
+        let worktree = remote_path(&self.remote_root, &self.local_root, &repo.toplevel);
+        let state_file = format!("{}.git-sync", self.remote_root);
+        let remote = self
+            .run_ssh_output(&format!(
+                "printf 'git=%s\\n' \"$(command -v git)\"; \
+             printf 'state=%s\\n' \"$(cat {state} 2>/dev/null)\"; \
+             printf 'head=%s\\n' \"$(git -C {dir} rev-parse -q --verify HEAD 2>/dev/null)\"",
+                state = shell_quote(&state_file),
+                dir = shell_quote(&worktree),
+            ))
+            .map_err(step_error(
+                OffloadError::Sync,
+                "cannot inspect the remote checkout",
+            ))?;
+        let remote_value = |key: &str| {
+            remote
+                .lines()
+                .find_map(|line| line.strip_prefix(key)?.strip_prefix('='))
+                .unwrap_or("")
+                .trim()
+                .to_string()
+        };
+
+        if remote_value("git").is_empty() {
+            warn!(
+                "git is not installed on {}, falling back to rsync",
+                self.host.display_name()
+            );
+            return Ok(false);
+        }
+
+        // Checked out are exactly the files git knows about, the rsync filters don't apply
+        let offloadignore = repo
+            .toplevel
+            .join(sync_filter::OFFLOAD_IGNORE_FILE)
+            .is_file()
+            || self
+                .local_dir
+                .join(sync_filter::OFFLOAD_IGNORE_FILE)
+                .is_file();
+        if !self.sync_excludes.is_empty() || offloadignore {
+            warn!(
+                "`sync.exclude` and {} are not applied by the git sync method, tracked and \
+                 untracked files that git doesn't ignore are synced",
+                sync_filter::OFFLOAD_IGNORE_FILE
             );
         }
 
+        let remote_head = remote_value("head");
+        if remote_head == base && remote_value("state") == state {
+            debug!("Remote checkout is up to date");
+            return Ok(true);
+        }
+
+        // Send the commits the remote checkout is missing
+        let known = match remote_head.as_str() {
+            "" => None,
+            head if repo.has_commit(head) => Some(head),
+            head => {
+                warn!(
+                    "Remote checkout is at {}, which is unknown locally, falling back to rsync",
+                    head
+                );
+                // Start over with a fresh checkout next time
+                self.run_ssh_command(
+                    &format!("rm -rf {}/.git", shell_quote(&worktree)),
+                    false,
+                    &[],
+                )
+                .map_err(step_error(
+                    OffloadError::Sync,
+                    "cannot remove the remote checkout",
+                ))?;
+                return Ok(false);
+            }
+        };
+        if !known.is_some_and(|known| repo.is_ancestor(&base, known)) {
+            match known {
+                Some(known) => info!("Sending git history since {}...", known),
+                None => info!("Sending git history for the initial checkout..."),
+            }
+            let bundle_file = format!("{}.bundle", self.remote_root);
+            transfer::pipe_to_remote(
+                self.transport.as_ref(),
+                &mut repo.bundle_command(known),
+                &[],
+                &format!(
+                    "cd {dir} && (test -d .git || git init -q) && cat > {bundle} && \
+                     git fetch -q {bundle}; status=$?; rm -f {bundle}; exit $status",
+                    dir = shell_quote(&worktree),
+                    bundle = shell_quote(&bundle_file),
+                ),
+            )?;
+        }
+
+        // Ignored files like `target/` survive the reset, untracked ones are sent again below
+        self.run_ssh_command(
+            &format!(
+                "cd {} && git reset -q --hard {} && git clean -q -fd -e target/",
+                shell_quote(&worktree),
+                base
+            ),
+            false,
+            &[],
+        )
+        .map_err(step_error(
+            OffloadError::Sync,
+            "cannot reset the remote checkout",
+        ))?;
+
+        if !diff.is_empty() {
+            debug!("Applying {} bytes of local changes", diff.len());
+            self.run_ssh_input(
+                &format!(
+                    "cd {} && git apply --binary --whitespace=nowarn",
+                    shell_quote(&worktree)
+                ),
+                &diff,
+            )
+            .map_err(step_error(
+                OffloadError::Sync,
+                "cannot apply the local changes on the remote",
+            ))?;
+        }
+
+        // The cargo config is synced on its own, filtered
+        let local_configs: Vec<PathBuf> = cargo_config::FILE_NAMES
+            .iter()
+            .map(|name| self.local_dir.join(".cargo").join(name))
+            .collect();
+        let untracked: Vec<String> = untracked
+            .iter()
+            .filter(|file| !local_configs.contains(&repo.toplevel.join(file)))
+            .map(|file| file.to_string_lossy().to_string())
+            .collect();
+        if !untracked.is_empty() {
+            debug!("Sending {} untracked files", untracked.len());
+            self.transport.sync_up(&SyncRequest {
+                local_dir: &repo.toplevel,
+                remote_dir: &worktree,
+                selection: Selection::Files(&untracked),
+                excludes: &[],
+                rsync: false,
+                progress: None,
+            })?;
+        }
+
+        self.run_ssh_command(
+            &format!("printf %s {} > {}", state, shell_quote(&state_file)),
+            false,
+            &[],
+        )
+        .map_err(step_error(
+            OffloadError::Sync,
+            "cannot record the sync state",
+        ))?;
+
+        Ok(true)
+    }
+
+    /// Manifest of the files in `dirs` and the changes needed to bring the remote copy up
+    /// to date with them, based on the manifest stored by the previous builtin sync
+    fn builtin_plan(
+        &self,
+        dirs: &[&Path],
+    ) -> Result<(delta::Manifest, Plan), Box<dyn std::error::Error>> {
+        // A manifest without the directory it describes, e.g. after a clean, is stale
+        let remote = self
+            .run_ssh_output(&format!(
+                "if [ -d {} ]; then cat {} 2>/dev/null; fi; true",
+                shell_quote(&self.remote_root),
+                shell_quote(&self.manifest_file())
+            ))
+            .map_err(step_error(
+                OffloadError::Sync,
+                "cannot read the remote manifest",
+            ))?;
+        // Directories synced another way, e.g. with git, are left alone
+        let prefixes: Vec<PathBuf> = dirs
+            .iter()
+            .map(|dir| {
+                dir.strip_prefix(&self.local_root)
+                    .unwrap_or(dir)
+                    .to_path_buf()
+            })
+            .collect();
+        let remote: delta::Manifest = delta::parse_manifest(&remote)
+            .into_iter()
+            .filter(|(path, _)| {
+                prefixes
+                    .iter()
+                    .any(|prefix| Path::new(path).starts_with(prefix))
+            })
+            .collect();
+
+        // Unchanged files keep the hash the remote manifest has for them
+        let local = delta::scan(
+            &self.local_root,
+            dirs,
+            self.sync_gitignore,
+            &self.sync_excludes,
+            &remote,
+        )
+        .map_err(|e| OffloadError::Sync(format!("{:#}", e)))?;
+        let plan = Plan::new(&local, &remote);
+
+        Ok((local, plan))
+    }
+
+    /// Sync `dirs` without rsync: content the remote already has is copied there, the
+    /// remaining files are sent as one compressed tar stream
+    fn sync_builtin(&self, dirs: &[&Path]) -> Result<(), Box<dyn std::error::Error>> {
+        let (local, plan) = self.builtin_plan(dirs)?;
+        if plan.is_empty() {
+            debug!("Remote copy is up to date");
+            return Ok(());
+        }
+        debug!(
+            "Replacing {}, copying {}, sending {} and deleting {} paths",
+            plan.replaced.len(),
+            plan.copies.len(),
+            plan.uploads.len(),
+            plan.deletes.len()
+        );
+
+        // Dropping the manifest first makes an interrupted sync start over next time
+        let mut script = format!("rm -f {}\n", shell_quote(&self.manifest_file()));
+        for path in &plan.replaced {
+            script.push_str(&format!("rm -rf {} || exit 1\n", shell_quote(path)));
+        }
+        for (from, to) in &plan.copies {
+            let to = shell_quote(to);
+            script.push_str(&format!(
+                "mkdir -p \"$(dirname {to})\" && cp {} {to} || exit 1\n",
+                shell_quote(from)
+            ));
+        }
+        for path in &plan.deletes {
+            script.push_str(&format!("rm -rf {}\n", shell_quote(path)));
+        }
+        self.run_ssh_input(
+            &format!("cd {} && sh -s", shell_quote(&self.remote_root)),
+            script.as_bytes(),
+        )
+        .map_err(step_error(
+            OffloadError::Sync,
+            "cannot copy and delete files on the remote",
+        ))?;
+
+        if !plan.uploads.is_empty() {
+            info!(
+                "Sending {} files ({})...",
+                plan.uploads.len(),
+                format_size(delta::total_size(&local, &plan.uploads))
+            );
+            self.transport.sync_up(&SyncRequest {
+                local_dir: &self.local_root,
+                remote_dir: &self.remote_root,
+                selection: Selection::Files(&plan.uploads),
+                excludes: &[],
+                rsync: false,
+                progress: None,
+            })?;
+        }
+
+        self.run_ssh_input(
+            &format!(
+                "cat > {0}.tmp && mv {0}.tmp {0}",
+                shell_quote(&self.manifest_file())
+            ),
+            delta::format_manifest(&local).as_bytes(),
+        )
+        .map_err(step_error(
+            OffloadError::Sync,
+            "cannot write the remote manifest",
+        ))
+    }
+
+    /// Manifest of the files synced by the builtin method, next to the remote directory
+    fn manifest_file(&self) -> String {
+        format!("{}.manifest", self.remote_root)
+    }
+
+    /// Upload the project's cargo config without the keys that only apply locally. It is
+    /// excluded from the rsync run, but a git checkout contains it if it is committed, so
+    /// any unfiltered copy under either name is removed first.
+    fn sync_cargo_config(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let remote_cargo_dir = format!("{}/.cargo", self.remote_dir);
+        let remote_config = format!("{}/config.toml", remote_cargo_dir);
+        let remote_configs: Vec<String> = cargo_config::FILE_NAMES
+            .iter()
+            .map(|name| format!("{}/{}", remote_cargo_dir, name))
+            .collect();
+        let remove_configs = format!("rm -f {}", shell_join(&remote_configs));
+
+        let Some(path) = &self.cargo_config else {
+            return self
+                .run_ssh_command(&remove_configs, false, &[])
+                .map_err(step_error(
+                    OffloadError::Sync,
+                    "cannot remove the stale cargo config",
+                ));
+        };
+
+        let (mut config, dropped) = cargo_config::load_filtered(path, &self.cargo_config_deny)
+            .map_err(|e| OffloadError::Sync(format!("{:#}", e)))?;
+        for key in dropped {
+            warn!("Not syncing `{}` from {}", key, path.display());
+        }
+
+        // Linkers are looked up in the image with container builds, which isn't running yet
+        let linkers = cargo_config::absolute_linkers(&config);
+        if !linkers.is_empty() && self.container.is_none() {
+            let missing = self
+                .run_ssh_output(&format!(
+                    "for linker in {}; do [ -e \"$linker\" ] || printf '%s\\n' \"$linker\"; done",
+                    shell_join(&linkers)
+                ))
+                .map_err(step_error(OffloadError::Sync, "cannot check the linkers"))?;
+            let missing: Vec<&str> = missing.lines().collect();
+            for (key, linker) in cargo_config::remove_linkers(&mut config, |l| missing.contains(&l))
+            {
+                warn!(
+                    "Not syncing `{}` from {}: {} does not exist on {}",
+                    key,
+                    path.display(),
+                    linker,
+                    self.host.display_name()
+                );
+            }
+        }
+        let filtered = toml::to_string(&config).map_err(|e| {
+            OffloadError::Sync(format!("Cannot serialize {}: {}", path.display(), e))
+        })?;
+
+        debug!("Syncing {} to {}", path.display(), remote_config);
+        self.run_ssh_input(
+            &format!(
+                "mkdir -p {} && {} && cat > {}",
+                shell_quote(&remote_cargo_dir),
+                remove_configs,
+                shell_quote(&remote_config)
+            ),
+            filtered.as_bytes(),
+        )
+        .map_err(step_error(
+            OffloadError::Sync,
+            "cannot upload the cargo config",
+        ))
+    }
+
+    /// The project and its external path dependencies with their remote directories
+    fn sync_dirs(&self) -> Vec<(&Path, String)> {
+        let mut dirs = vec![(self.local_dir.as_path(), self.remote_dir.clone())];
+        for dependency in &self.path_dependencies {
+            let remote = remote_path(&self.remote_root, &self.local_root, dependency);
+            dirs.push((dependency.as_path(), remote));
+        }
+        dirs
+    }
+
+    fn sync_dir(&self, local: &Path, remote: &str) -> Result<(), Box<dyn std::error::Error>> {
+        let (rules, excludes) = self.sync_filters(local)?;
+        self.transport.sync_up(&SyncRequest {
+            local_dir: local,
+            remote_dir: remote,
+            selection: Selection::Mirror { rules: &rules },
+            excludes: &excludes,
+            rsync: true,
+            progress: self.progress(),
+        })?;
+
+        Ok(())
+    }
+
+    /// Ignore rules for mirroring `local`, which rsync reads from stdin, and the patterns
+    /// excluded everywhere, like the target directory and other build artifacts
+    fn sync_filters(
+        &self,
+        local: &Path,
+    ) -> Result<(String, Vec<String>), Box<dyn std::error::Error>> {
+        let mut rules = String::new();
+        let ignored = sync_filter::exclude_rules(local, self.sync_gitignore)
+            .map_err(|e| OffloadError::Sync(format!("{:#}", e)))?;
+        for rule in ignored {
+            rules.push_str(&rule);
+            rules.push('\n');
+        }
+
+        let excludes = sync_filter::DEFAULT_EXCLUDES
+            .iter()
+            .map(|pattern| pattern.to_string())
+            .chain(self.sync_excludes.iter().cloned())
+            .collect();
+
+        Ok((rules, excludes))
+    }
+
+    /// List the files `sync_source` would transfer or delete, without changing anything
+    pub fn sync_dry_run(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if self.builtin_sync {
+            return self.sync_dry_run_builtin();
+        }
+
+        let mut total_files = 0;
+        let mut total_bytes = 0;
+
+        for (local, remote) in self.sync_dirs() {
+            let (rules, excludes) = self.sync_filters(local)?;
+            let changes = self.transport.sync_up_dry_run(&SyncRequest {
+                local_dir: local,
+                remote_dir: &remote,
+                selection: Selection::Mirror { rules: &rules },
+                excludes: &excludes,
+                rsync: true,
+                progress: None,
+            })?;
+
+            let prefix = local.strip_prefix(&self.local_root).unwrap_or(local);
+            for change in changes {
+                match change {
+                    Change::Send { path, size } => {
+                        println!("{:>10}  {}", format_size(size), prefix.join(path).display());
+                        total_files += 1;
+                        total_bytes += size;
+                    }
+                    Change::Delete { path } => {
+                        println!("{:>10}  {}", "delete", prefix.join(path).display());
+                    }
+                }
+            }
+        }
+
+        if let Some(path) = &self.cargo_config {
+            println!("{:>10}  {} (filtered)", "", path.display());
+        }
+        println!(
+            "{} files, {} would be transferred to {}",
+            total_files,
+            format_size(total_bytes),
+            self.host.display_name()
+        );
+
+        Ok(())
+    }
+
+    fn sync_dry_run_builtin(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let dirs: Vec<&Path> = self
+            .sync_dirs()
+            .into_iter()
+            .map(|(local, _)| local)
+            .collect();
+        let (local, plan) = self.builtin_plan(&dirs)?;
+
+        for path in &plan.replaced {
+            println!("{:>10}  {}", "replace", path);
+        }
+        for path in &plan.uploads {
+            let size = local.get(path).map_or(0, |entry| entry.size);
+            println!("{:>10}  {}", format_size(size), path);
+        }
+        for (from, to) in &plan.copies {
+            println!("{:>10}  {} (from {})", "copy", to, from);
+        }
+        for path in &plan.deletes {
+            println!("{:>10}  {}", "delete", path);
+        }
+
+        if let Some(path) = &self.cargo_config {
+            println!("{:>10}  {} (filtered)", "", path.display());
+        }
+        println!(
+            "{} files, {} would be transferred to {}",
+            plan.uploads.len(),
+            format_size(delta::total_size(&local, &plan.uploads)),
+            self.host.display_name()
+        );
+
         Ok(())
     }
 
     pub fn setup_toolchain(&self) -> Result<(), Box<dyn std::error::Error>> {
+        self.create_shared_dirs()?;
+        self.start_container()?;
+
         match &self.toolchain {
             Some(toolchain) => {
                 info!("Setting up toolchain {} on remote...", toolchain);
                 self.run_ssh_command(
-                    &format!(
+                    &self.in_container(&format!(
                         "cd {} && rustup toolchain install {}",
-                        self.remote_dir, toolchain
-                    ),
+                        shell_quote(&self.remote_dir),
+                        shell_quote(toolchain)
+                    )),
                     false,
                     &[],
                 )?;
@@ -140,288 +965,530 @@ impl CargoOffload {
         let target_install_cmd = if let Some(toolchain) = &self.toolchain {
             format!(
                 "cd {} && rustup target add {} --toolchain {}",
-                self.remote_dir, self.target, toolchain
+                shell_quote(&self.remote_dir),
+                shell_quote(&self.target),
+                shell_quote(toolchain)
             )
         } else {
             format!(
                 "cd {} && rustup target add {}",
-                self.remote_dir, self.target
+                shell_quote(&self.remote_dir),
+                shell_quote(&self.target)
             )
         };
 
-        self.run_ssh_command(&target_install_cmd, false, &[])?;
+        self.run_ssh_command(&self.in_container(&target_install_cmd), false, &[])?;
+
+        if let Some(sccache) = &self.sccache {
+            self.setup_sccache(sccache)?;
+        }
+        Ok(())
+    }
+
+    /// Install sccache on the remote unless it is there already, and make sure its server
+    /// runs with our cache settings
+    fn setup_sccache(&self, sccache: &Sccache) -> Result<(), Box<dyn std::error::Error>> {
+        let setup = self.in_container(&sccache.setup_command());
+        if self.run_ssh_output(&setup)?.trim() == sccache::MISSING {
+            info!("Installing sccache on remote, this takes a few minutes...");
+            self.run_ssh_command(
+                &self.in_container("cargo install --locked sccache"),
+                false,
+                &[],
+            )?;
+            self.run_ssh_output(&setup)?;
+        }
         Ok(())
     }
 
+    /// Current counters of the remote sccache server. `None` if sccache is disabled, its
+    /// statistics are not asked for or cannot be queried.
+    fn sccache_stats(&self) -> Option<Stats> {
+        let sccache = self.sccache.as_ref().filter(|sccache| sccache.stats)?;
+
+        let mut command = String::new();
+        for var in sccache.server_env_vars() {
+            command.push_str(&shell_env_assignment(&var).ok()?);
+            command.push(' ');
+        }
+        command.push_str("sccache --show-stats");
+
+        match self.run_ssh_output(&self.in_container(&command)) {
+            Ok(output) => Stats::parse(&output),
+            Err(e) => {
+                debug!("Cannot read sccache statistics: {}", e);
+                None
+            }
+        }
+    }
+
+    /// Run `f`, then report how many of the compilations it caused sccache had cached
+    fn with_sccache_report<T>(
+        &self,
+        f: impl FnOnce() -> Result<T, Box<dyn std::error::Error>>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let before = self.sccache_stats();
+        let result = f()?;
+
+        if let (Some(before), Some(after)) = (before, self.sccache_stats()) {
+            let stats = after.since(before);
+            // Nothing to report if nothing was compiled
+            if let Some(percent) = (stats.hits * 100).checked_div(stats.hits + stats.misses) {
+                eprintln!(
+                    "sccache: {} hits, {} misses ({}% cached)",
+                    stats.hits, stats.misses, percent
+                );
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Create the shared `CARGO_HOME` and sccache directory if they don't exist yet. New
+    /// files inherit their group thanks to the setgid bit, and cargo and the sccache server
+    /// run with a umask keeping them group writable.
+    fn create_shared_dirs(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let dirs: Vec<String> = self
+            .host
+            .cargo_home
+            .iter()
+            .chain(self.sccache.as_ref().map(|sccache| &sccache.dir))
+            .map(|dir| shell_quote(dir))
+            .collect();
+        if dirs.is_empty() {
+            return Ok(());
+        }
+
+        let command = dirs
+            .iter()
+            .map(|dir| {
+                format!(
+                    "{{ [ -d {0} ] || {{ mkdir -p {0} && chmod 2775 {0}; }}; }}",
+                    dir
+                )
+            })
+            .collect::<Vec<_>>()
+            .join(" && ");
+        self.run_ssh_command(&command, false, &[])
+    }
+
+    /// Create the build container unless it is already running
+    fn start_container(&self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Some(container) = &self.container {
+            info!(
+                "Starting container {} ({}) on remote...",
+                container.name(),
+                container.image()
+            );
+            self.run_ssh_command(&container.start_command(), false, &[])?;
+        }
+        Ok(())
+    }
+
+    /// `command` as run in the build container, or unchanged without one
+    fn in_container(&self, command: &str) -> String {
+        match &self.container {
+            Some(container) => container.exec_command(command),
+            None => command.to_string(),
+        }
+    }
+
     pub fn run_cargo_command(
         &self,
         subcommand: &str,
         args: &[String],
-        env_vars: &[String],
-        forward_ports: &[String],
+        forward_ports: &[Forward],
     ) -> Result<(), Box<dyn std::error::Error>> {
         info!("Running cargo {} on remote...", subcommand);
 
-        let mut cargo_args = Vec::new();
+        let mut cargo_args = vec![subcommand.to_string()];
+        cargo_args.extend(self.with_default_target(args));
 
-        // Add toolchain prefix
-        if let Some(toolchain) = &self.toolchain {
-            cargo_args.push(format!("+{}", toolchain));
-        }
+        self.run_remote_cargo(&cargo_args, &[], forward_ports)?;
+        debug!("Cargo {} completed successfully on remote", subcommand);
 
-        cargo_args.push(subcommand.to_string());
+        Ok(())
+    }
 
-        // Parse args to insert target if needed and not already present
-        let mut has_target = false;
-        let mut final_args = Vec::new();
+    /// Run `cargo build` on the remote and return the artifacts it reported
+    pub fn build(&self, args: &[String]) -> Result<Vec<Artifact>, Box<dyn std::error::Error>> {
+        info!("Running cargo build on remote...");
 
-        let mut i = 0;
-        while i < args.len() {
-            let arg = &args[i];
-            if arg == "--target" {
-                has_target = true;
-                final_args.push(arg.clone());
-                if i + 1 < args.len() {
-                    i += 1;
-                    final_args.push(args[i].clone());
-                }
-            } else {
-                final_args.push(arg.clone());
-            }
-            i += 1;
-        }
+        let (args, echo_json) = with_artifact_message_format(args);
+        let mut cargo_args = vec!["build".to_string()];
+        cargo_args.extend(self.with_default_target(&args));
+
+        let command = self.remote_cargo_command(&cargo_args, &[])?;
+        let collector = ArtifactCollector::new(
+            self.path_rewriter(false),
+            echo_json.then(|| self.path_rewriter(true)),
+            io::stdout(),
+        );
+        let artifacts = self.with_sccache_report(|| {
+            self.run_ssh_streaming(&command, &[], |stdout| collector.pump(stdout))
+        })?;
+        debug!(
+            "Cargo build completed successfully on remote, {} artifacts",
+            artifacts.len()
+        );
+
+        Ok(artifacts)
+    }
 
-        // Add default target if not specified
+    /// Add the default `--target` to `args` unless one is given already
+    fn with_default_target(&self, args: &[String]) -> Vec<String> {
+        let mut final_args = Vec::new();
+
+        // Parse args to insert target if needed and not already present
+        let has_target = args
+            .iter()
+            .take_while(|arg| *arg != "--")
+            .any(|arg| arg == "--target" || arg.starts_with("--target="));
         if !has_target {
-            cargo_args.push("--target".to_string());
-            cargo_args.push(self.target.clone());
+            final_args.push("--target".to_string());
+            final_args.push(self.target.clone());
         }
 
         // Add user arguments
-        cargo_args.extend(final_args);
+        final_args.extend(args.iter().cloned());
+        final_args
+    }
 
-        // Construct the command with environment variables
-        let env_vars_str = if !env_vars.is_empty() {
-            // Properly quote environment variables to handle spaces in values
-            let quoted_env_vars: Vec<String> = env_vars
-                .iter()
-                .map(|var| {
-                    // Split at the first equals sign
-                    if let Some(pos) = var.find('=') {
-                        let (name, value) = var.split_at(pos + 1);
-                        // Quote the value part if it contains spaces or special characters
-                        if value.contains(' ')
-                            || value.contains('"')
-                            || value.contains('\'')
-                            || value.contains('$')
-                            || value.contains('&')
-                            || value.contains('|')
-                        {
-                            // Use single quotes for values with spaces, escaping any single quotes in the value
-                            let escaped_value = value.replace('\'', "'\\''");
-                            format!("{}'{}'", name, escaped_value)
-                        } else {
-                            var.clone()
-                        }
-                    } else {
-                        // If there's no equals sign, just use as is
-                        var.clone()
-                    }
-                })
-                .collect();
-            format!("{} ", quoted_env_vars.join(" "))
+    /// Run an arbitrary cargo subcommand (`args[0]`) with its arguments passed on unchanged
+    pub fn run_cargo_passthrough(&self, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+        let subcommand = args.first().ok_or("No cargo subcommand given")?;
+        info!("Running cargo {} on remote...", subcommand);
+
+        // Subcommands take `--target` in different positions (`cargo nextest run --target`),
+        // so the default target is set through the environment, where it is overridable.
+        // It would also override a `build.target` from the synced cargo config.
+        let mut env_vars = Vec::new();
+        if !self.config_build_target {
+            env_vars.push(format!("CARGO_BUILD_TARGET={}", self.target));
+        }
+        self.run_remote_cargo(args, &env_vars, &[])?;
+        debug!("Cargo {} completed successfully on remote", subcommand);
+
+        Ok(())
+    }
+
+    /// Run `cargo <cargo_args>` in the remote project directory
+    fn run_remote_cargo(
+        &self,
+        cargo_args: &[String],
+        extra_env_vars: &[String],
+        forward_ports: &[Forward],
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let cargo_cmd = self.remote_cargo_command(cargo_args, extra_env_vars)?;
+        self.with_sccache_report(|| self.run_ssh_command(&cargo_cmd, true, forward_ports))
+    }
+
+    /// Shell command running `cargo <cargo_args>` in the remote project directory
+    fn remote_cargo_command(
+        &self,
+        cargo_args: &[String],
+        extra_env_vars: &[String],
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let mut global_args = Vec::new();
+
+        // Add toolchain prefix
+        if let Some(toolchain) = &self.toolchain {
+            global_args.push(format!("+{}", toolchain));
+        }
+
+        // Limit parallelism if the host profile asks for it, user env vars may still override it
+        let mut env_vars = Vec::new();
+        if let Some(jobs) = self.host.jobs {
+            env_vars.push(format!("CARGO_BUILD_JOBS={}", jobs));
+        }
+        if let Some(cargo_home) = &self.host.cargo_home {
+            env_vars.push(format!("CARGO_HOME={}", cargo_home));
+        }
+        env_vars.extend(extra_env_vars.iter().cloned());
+        env_vars.extend(self.env_vars.iter().cloned());
+
+        // Make panics and debuginfo of copied binaries refer to local paths. With sccache
+        // the wrapper adds the flag for the project's crates only, so that the cache keys
+        // of dependencies are the same for every project.
+        let local_root = self.local_root.to_string_lossy();
+        if let Some(sccache) = &self.sccache {
+            let remap = self
+                .remap_path_prefix
+                .then_some((self.remote_root.as_str(), local_root.as_ref()));
+            env_vars.splice(0..0, sccache.env_vars(remap));
+        } else if self.remap_path_prefix {
+            let remap = format!(
+                "--remap-path-prefix={}={}",
+                self.remote_root,
+                self.local_root.display()
+            );
+            if let Some(key) = self.add_remap_flag(&mut env_vars, &remap) {
+                let rustflags = toml::Value::Array(vec![toml::Value::String(remap)]);
+                global_args.push("--config".to_string());
+                global_args.push(format!("{}={}", key, rustflags));
+            }
+        }
+
+        // Construct the command with environment variables, quoting every value and argument
+        let mut env_vars_str = String::new();
+        for var in &env_vars {
+            env_vars_str.push_str(&shell_env_assignment(var)?);
+            env_vars_str.push(' ');
+        }
+
+        global_args.extend(self.remote_manifest_path(cargo_args)?);
+        // Files in the shared cargo home and sccache directory have to stay writable for
+        // the other users, the sccache server may be started by this command
+        let umask = if self.host.cargo_home.is_some() || self.sccache.is_some() {
+            "umask 002 && "
         } else {
-            String::new()
+            ""
         };
-
         let cargo_cmd = format!(
-            "cd {} && {}cargo {}",
-            self.remote_dir,
+            "cd {} && {}{}cargo {}",
+            shell_quote(&self.remote_work_dir),
+            umask,
             env_vars_str,
-            cargo_args.join(" ")
+            shell_join(&global_args)
         );
 
-        self.run_ssh_command(&cargo_cmd, true, forward_ports)?;
-        debug!("Cargo {} completed successfully on remote", subcommand);
+        Ok(self.in_container(&cargo_cmd))
+    }
 
-        Ok(())
+    /// Add `remap` to the rustflags cargo uses, which are the first of
+    /// `CARGO_ENCODED_RUSTFLAGS`, `RUSTFLAGS`, `target.<triple>.rustflags` (along with the
+    /// matching `target.<cfg>` ones) and `build.rustflags` that is set. Flags set through
+    /// `env_vars` are extended in place, otherwise the config key to append to is returned.
+    fn add_remap_flag(&self, env_vars: &mut [String], remap: &str) -> Option<String> {
+        // The last assignment of a variable is the one that takes effect
+        for (name, separator) in [("CARGO_ENCODED_RUSTFLAGS", '\x1f'), ("RUSTFLAGS", ' ')] {
+            let prefix = format!("{}=", name);
+            if let Some(var) = env_vars.iter_mut().rev().find(|v| v.starts_with(&prefix)) {
+                if var.len() > prefix.len() {
+                    var.push(separator);
+                }
+                var.push_str(remap);
+                return None;
+            }
+        }
+
+        let target_rustflags = self.cargo_config.as_deref().is_some_and(|path| {
+            cargo_config::load_filtered(path, &self.cargo_config_deny)
+                .is_ok_and(|(config, _)| cargo_config::has_target_rustflags(&config))
+        });
+        if target_rustflags {
+            let target = toml::Value::String(self.target.clone());
+            Some(format!("target.{}.rustflags", target))
+        } else {
+            Some("build.rustflags".to_string())
+        }
+    }
+
+    /// Point `--manifest-path` arguments at the synced copy of the manifest
+    fn remote_manifest_path(
+        &self,
+        args: &[String],
+    ) -> Result<Vec<String>, Box<dyn std::error::Error>> {
+        let to_remote = |path: &str| -> Result<String, Box<dyn std::error::Error>> {
+            let local = std::env::current_dir()?.join(path).canonicalize()?;
+            if !local.starts_with(&self.local_root) {
+                return Err(format!(
+                    "Manifest {} is outside of the project {}",
+                    local.display(),
+                    self.local_root.display()
+                )
+                .into());
+            }
+            Ok(remote_path(&self.remote_root, &self.local_root, &local))
+        };
+
+        let mut final_args = Vec::new();
+        let mut iter = args.iter();
+        while let Some(arg) = iter.next() {
+            if arg == "--" {
+                final_args.push(arg.clone());
+                final_args.extend(iter.by_ref().cloned());
+                break;
+            }
+
+            if arg == "--manifest-path" {
+                final_args.push(arg.clone());
+                if let Some(path) = iter.next() {
+                    final_args.push(to_remote(path)?);
+                }
+            } else if let Some(path) = arg.strip_prefix("--manifest-path=") {
+                final_args.push(format!("--manifest-path={}", to_remote(path)?));
+            } else {
+                final_args.push(arg.clone());
+            }
+        }
+
+        Ok(final_args)
     }
 
     pub fn toolchain_remote(&self, args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
         debug!("Running rustup toolchain command on remote...");
 
-        let toolchain_cmd = format!("rustup toolchain {}", args.join(" "));
-        self.run_ssh_command(&toolchain_cmd, true, &[])?;
+        self.create_shared_dirs()?;
+        self.start_container()?;
+        let toolchain_cmd = format!("rustup toolchain {}", shell_join(args));
+        self.run_ssh_command(&self.in_container(&toolchain_cmd), true, &[])?;
         debug!("Toolchain command completed successfully on remote");
 
         Ok(())
     }
 
+    /// Copy the executables and native libraries among `artifacts` to `target/offload/`
+    /// and return the artifacts with their local paths
     pub fn copy_artifacts(
         &self,
         args: &[String],
-        specific_bin: Option<&String>,
-        specific_example: Option<&String>,
-    ) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
-        let release = args.contains(&"--release".to_string());
-        let profile = if release { "release" } else { "debug" };
-        let remote_target_dir = format!("{}/target/{}", self.remote_dir, self.target);
-        let remote_profile_dir = format!("{}/{}", remote_target_dir, profile);
-
-        // Create local target directory structure in target/offload/{target_triple}/
-        let local_target_dir = format!("target/offload/{}", self.target);
-        let local_profile_dir = format!("{}/{}", local_target_dir, profile);
-        fs::create_dir_all(&local_profile_dir)?;
+        artifacts: &[Artifact],
+    ) -> Result<Vec<Artifact>, Box<dyn std::error::Error>> {
+        let remote_target_dir = format!("{}/target", self.remote_dir);
+        let local_target_dir = self.local_dir.join("target/offload");
+
+        // Only the project's own artifacts, dependencies stay on the remote
+        let artifacts: Vec<&Artifact> = artifacts
+            .iter()
+            .filter(|artifact| artifact.manifest_path.starts_with(&self.remote_dir))
+            .collect();
+        let files: Vec<&Path> = artifacts
+            .iter()
+            .flat_map(|artifact| artifact.files_to_copy())
+            .filter_map(|path| path.strip_prefix(&remote_target_dir).ok())
+            .collect();
 
         info!("Copying artifacts from remote target directory...");
 
-        // Use a single rsync call to copy the entire target directory
-        let mut rsync_cmd = Command::new("rsync");
-        rsync_cmd
-            .arg("-a")
-            .arg("--delete")
-            .arg("--compress")
-            .arg("-e")
-            .arg(format!("ssh -p {}", self.port))
-            .arg(&self.progress_flag)
-            .arg("--exclude=.cargo-lock")
-            .arg("--exclude=*.d"); // TODO: can we improve this by not excluding?
-
-        // Add exclusions for large build artifacts unless --copy-all-artifacts is specified
-        if !self.copy_all_artifacts {
-            rsync_cmd
-                .arg("--exclude=build/")
-                .arg("--exclude=deps/")
-                .arg("--exclude=incremental/");
-        }
-
-        // Set source and destination
-        rsync_cmd
-            .arg(format!("{}:{}/", self.host, remote_profile_dir))
-            .arg(format!("{}/", local_profile_dir))
-            .stdout(Stdio::inherit())
-            .stderr(Stdio::inherit());
-
-        let output = rsync_cmd.output().context("rsync failed")?;
-        if !output.status.success() {
-            return Err(format!(
-                "Failed to copy artifacts: {}",
-                String::from_utf8_lossy(&output.stderr)
-            )
-            .into());
+        if self.copy_all_artifacts {
+            self.copy_profile_dir(args, &local_target_dir)?;
+        } else if !files.is_empty() {
+            let files: Vec<String> = files
+                .iter()
+                .map(|file| file.to_string_lossy().to_string())
+                .collect();
+            debug!("Artifacts to copy:\n{}", files.join("\n"));
+
+            self.transport.sync_down(&SyncRequest {
+                local_dir: &local_target_dir,
+                remote_dir: &remote_target_dir,
+                selection: Selection::Files(&files),
+                excludes: &[],
+                rsync: !self.builtin_sync,
+                progress: self.progress(),
+            })?;
         }
 
+        let local_artifacts: Vec<Artifact> = artifacts
+            .iter()
+            .map(|artifact| {
+                artifact.map_paths(|path| {
+                    let relative = path.strip_prefix(&remote_target_dir).ok()?;
+                    Some(local_target_dir.join(relative))
+                })
+            })
+            .collect();
+
         // Make binaries and examples executable on Unix systems
         #[cfg(unix)]
         {
             use std::os::unix::fs::PermissionsExt;
 
-            // Find and make executable all binary files in the target directory
-            let make_executable = |path: &Path| {
-                if let Ok(metadata) = fs::metadata(path) {
-                    // Only make executable if it's a file and not a directory
-                    if metadata.is_file() {
-                        let mut perms = metadata.permissions();
-                        perms.set_mode(0o755);
-                        let _ = fs::set_permissions(path, perms);
-                    }
-                }
-            };
-
-            // Make binaries in root directory executable
-            if let Ok(entries) = fs::read_dir(&local_profile_dir) {
-                for entry in entries.flatten() {
-                    if let Ok(file_type) = entry.file_type() {
-                        if file_type.is_file() {
-                            make_executable(&entry.path());
-                        }
-                    }
-                }
-            }
-
-            // Make examples executable
-            let examples_dir = format!("{}/examples", local_profile_dir);
-            if Path::new(&examples_dir).exists() {
-                if let Ok(entries) = fs::read_dir(&examples_dir) {
-                    for entry in entries.flatten() {
-                        if let Ok(file_type) = entry.file_type() {
-                            if file_type.is_file() {
-                                make_executable(&entry.path());
-                            }
-                        }
-                    }
+            for executable in local_artifacts.iter().filter_map(|a| a.executable.as_ref()) {
+                if let Ok(metadata) = fs::metadata(executable) {
+                    let mut perms = metadata.permissions();
+                    perms.set_mode(0o755);
+                    let _ = fs::set_permissions(executable, perms);
                 }
             }
         }
 
-        // Determine which binary to return for the run command
-        let mut result_paths = Vec::new();
+        info!(
+            "Successfully copied {} artifacts from remote target directory",
+            files.len()
+        );
+        Ok(local_artifacts)
+    }
+
+    /// Mirror the whole remote profile directory, including dependencies and build scripts
+    fn copy_profile_dir(
+        &self,
+        args: &[String],
+        local_target_dir: &Path,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let profile = profile_dir_name(args)?;
+        let remote_profile_dir = format!("{}/target/{}/{}", self.remote_dir, self.target, profile);
+        let local_profile_dir = local_target_dir.join(&self.target).join(&profile);
+
+        self.transport.sync_down(&SyncRequest {
+            local_dir: &local_profile_dir,
+            remote_dir: &remote_profile_dir,
+            selection: Selection::Mirror { rules: "" },
+            excludes: &[".cargo-lock".to_string(), "*.d".to_string()],
+            rsync: !self.builtin_sync,
+            progress: self.progress(),
+        })?;
 
-        if let Some(bin_name) = specific_bin {
-            // If a specific binary was requested
-            let bin_path = PathBuf::from(format!("{}/{}", local_profile_dir, bin_name));
-            if bin_path.exists() {
-                result_paths.push(bin_path);
-            } else {
-                return Err(format!("Binary '{}' not found after copy", bin_name).into());
-            }
-        } else if let Some(example_name) = specific_example {
-            // If a specific example was requested
-            let example_path =
-                PathBuf::from(format!("{}/examples/{}", local_profile_dir, example_name));
-            if example_path.exists() {
-                result_paths.push(example_path);
-            } else {
-                return Err(format!("Example '{}' not found after copy", example_name).into());
-            }
-        } else {
-            // For general build, just return success without specific paths
-            // Find all executables in the root directory (not in subdirectories)
-            if let Ok(entries) = fs::read_dir(&local_profile_dir) {
-                for entry in entries.flatten() {
-                    if let Ok(file_type) = entry.file_type() {
-                        if file_type.is_file() {
-                            let path = entry.path();
-                            // Skip files that start with "lib" as they're likely libraries
-                            if let Some(file_name) = path.file_name() {
-                                let name = file_name.to_string_lossy();
-                                if !name.starts_with("lib") {
-                                    result_paths.push(path);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
+        Ok(())
+    }
 
-            // Also check examples directory
-            let examples_dir = format!("{}/examples", local_profile_dir);
-            if Path::new(&examples_dir).exists() {
-                if let Ok(entries) = fs::read_dir(&examples_dir) {
-                    for entry in entries.flatten() {
-                        if let Ok(file_type) = entry.file_type() {
-                            if file_type.is_file() {
-                                result_paths.push(entry.path());
-                            }
-                        }
-                    }
-                }
-            }
+    /// Copy the artifact paths configured for a passthrough subcommand (`[commands.<name>]`)
+    pub fn copy_command_artifacts(
+        &self,
+        subcommand: &str,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let Some(paths) = self.command_artifacts.get(subcommand) else {
+            return Ok(());
+        };
+        if paths.is_empty() {
+            return Ok(());
         }
 
-        info!("Successfully copied artifacts from remote target directory");
-        Ok(result_paths)
+        let remote_target_dir = format!("{}/target/{}", self.remote_dir, self.target);
+        let local_target_dir = self.local_dir.join("target/offload").join(&self.target);
+
+        info!("Copying cargo {} artifacts from remote...", subcommand);
+        self.transport.sync_down(&SyncRequest {
+            local_dir: &local_target_dir,
+            remote_dir: &remote_target_dir,
+            selection: Selection::Trees(paths),
+            excludes: &[],
+            rsync: !self.builtin_sync,
+            progress: self.progress(),
+        })?;
+
+        info!(
+            "Copied {} to {}",
+            paths.join(", "),
+            local_target_dir.display()
+        );
+        Ok(())
     }
 
     pub fn clean(&self) -> Result<(), Box<dyn std::error::Error>> {
         info!("Cleaning remote build directory...");
 
-        // Clean remote directory
-        self.run_ssh_command(&format!("rm -rf {}", self.remote_dir), false, &[])?;
+        if let Some(container) = &self.container {
+            info!("Removing container {}...", container.name());
+            self.run_ssh_command(&container.remove_command(), false, &[])?;
+        }
+
+        // Clean remote directory, including synced path dependencies
+        self.run_ssh_command(
+            &format!(
+                "rm -rf {} {} {}",
+                shell_quote(&self.remote_root),
+                shell_quote(&self.manifest_file()),
+                shell_quote(&format!("{}.git-sync", self.remote_root))
+            ),
+            false,
+            &[],
+        )?;
 
         // Clean local offload target directory
-        let local_offload_dir = "target/offload";
-        if Path::new(local_offload_dir).exists() {
+        let local_offload_dir = self.local_dir.join("target/offload");
+        if local_offload_dir.exists() {
             info!("Cleaning local offload directory...");
             fs::remove_dir_all(local_offload_dir)?;
         }
@@ -430,6 +1497,134 @@ impl CargoOffload {
         Ok(())
     }
 
+    pub fn status(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let remote_state = self.run_ssh_output(&format!(
+            "if [ -d {0} ]; then du -sh {0} | cut -f1; else echo missing; fi",
+            shell_quote(&self.remote_root)
+        ))?;
+        let remote_state = match remote_state.trim() {
+            "missing" => "not synced yet".to_string(),
+            size => format!("synced ({})", size),
+        };
+
+        println!("Local project:  {}", self.local_dir.display());
+        println!("Host:           {}", self.host.display_name());
+        println!("Remote dir:     {}", self.remote_dir);
+        for dependency in &self.path_dependencies {
+            println!("Path dep:       {}", dependency.display());
+        }
+        println!("Remote state:   {}", remote_state);
+        if let Some(container) = &self.container {
+            println!(
+                "Container:      {} ({})",
+                container.name(),
+                container.image()
+            );
+        }
+        println!("Target:         {}", self.target);
+        println!(
+            "Toolchain:      {}",
+            self.toolchain.as_deref().unwrap_or("remote default")
+        );
+
+        Ok(())
+    }
+
+    /// Show how much space the remote cargo home and the sccache cache take
+    pub fn cache_stats(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let cargo_home = match &self.host.cargo_home {
+            Some(cargo_home) => shell_quote(cargo_home),
+            None => "\"${CARGO_HOME:-$HOME/.cargo}\"".to_string(),
+        };
+        let mut script = format!(
+            "home={}; echo \"home\t$home\"; \
+             for dir in . registry/index registry/cache registry/src git/db git/checkouts; do \
+             if [ -d \"$home/$dir\" ]; then echo \"$dir\t$(du -sk \"$home/$dir\" | cut -f1)\"; fi; \
+             done",
+            cargo_home
+        );
+        // The container has its own cargo home unless a shared one is mounted into it. Only
+        // a running container is asked, creating one just for its sizes would be wasteful.
+        let stopped_container = match (&self.container, &self.host.cargo_home) {
+            (Some(container), None) => {
+                script = format!(
+                    "if {}; then {}; fi",
+                    container.running_command(),
+                    container.exec_command(&script)
+                );
+                Some(container.name())
+            }
+            _ => None,
+        };
+        // Shared directories are mounted at the same path, so they are measured on the host
+        if let Some(sccache) = &self.sccache {
+            script.push_str(&format!(
+                "; if [ -d {0} ]; then echo \"sccache\t$(du -sk {0} | cut -f1)\"; fi",
+                shell_quote(&sccache.dir)
+            ));
+        }
+        let output = self.run_ssh_output(&script)?;
+
+        // `<name>\t<size in KiB>` per existing directory, after the resolved cargo home
+        let mut home = String::new();
+        let mut sizes = Vec::new();
+        for line in output.lines() {
+            let Some((name, value)) = line.split_once('\t') else {
+                continue;
+            };
+            match (name, value.trim().parse::<u64>()) {
+                ("home", _) => home = value.to_string(),
+                (name, Ok(kib)) => sizes.push((name, kib * 1024)),
+                _ => {}
+            }
+        }
+        let size_of = |name: &str| {
+            sizes
+                .iter()
+                .find(|(dir, _)| *dir == name)
+                .map(|(_, size)| *size)
+        };
+
+        match stopped_container {
+            Some(name) if home.is_empty() => {
+                println!(
+                    "Cargo home:     in container {}, which is not running",
+                    name
+                )
+            }
+            _ => {
+                println!(
+                    "Cargo home:     {}{}",
+                    home,
+                    if self.host.cargo_home.is_some() {
+                        " (shared)"
+                    } else {
+                        ""
+                    }
+                );
+                match size_of(".") {
+                    Some(size) => println!("Size:           {}", format_size(size)),
+                    None => println!("Size:           missing"),
+                }
+            }
+        }
+        for (name, size) in &sizes {
+            if !matches!(*name, "." | "sccache") {
+                println!("  {:<16}{:>10}", name, format_size(*size));
+            }
+        }
+
+        if let Some(sccache) = &self.sccache {
+            println!("sccache dir:    {}", sccache.dir);
+            match size_of("sccache") {
+                Some(size) => println!("sccache size:   {}", format_size(size)),
+                None => println!("sccache size:   missing"),
+            }
+        }
+
+        Ok(())
+    }
+
     pub fn run_binary(
         &self,
         binary_path: &Path,
@@ -453,71 +1648,102 @@ impl CargoOffload {
         &self,
         command: &str,
         print_output: bool,
-        forward_ports: &[String],
+        forward_ports: &[Forward],
     ) -> Result<(), Box<dyn std::error::Error>> {
-        let mut ssh_cmd = Command::new("ssh");
-
-        // Force pseudo-terminal allocation for interactive programs
-        ssh_cmd.arg("-t");
-
-        if !forward_ports.is_empty() {
-            let mut ssh_forward_args = Vec::new();
-            for port_spec in forward_ports {
-                // Parse format: local_port:remote_port or just port (assumes same port on both sides)
-                let parts: Vec<&str> = port_spec.split(':').collect();
-                match parts.len() {
-                    1 => {
-                        // Same port on both sides
-                        ssh_forward_args.push("-L".to_string());
-                        ssh_forward_args.push(format!("{}:localhost:{}", parts[0], parts[0]));
-                    }
-                    2 => {
-                        // Different ports: local:remote
-                        ssh_forward_args.push("-L".to_string());
-                        ssh_forward_args.push(format!("{}:localhost:{}", parts[0], parts[1]));
-                    }
-                    _ => {
-                        return Err(format!(
-                            "Invalid port forwarding specification: {}",
-                            port_spec
-                        )
-                        .into());
-                    }
-                }
+        if print_output {
+            // Stream the output while pointing remote project paths at the local checkout
+            return self.run_ssh_streaming(command, forward_ports, |stdout| {
+                self.path_rewriter(self.quiet).pump(stdout, io::stdout())
+            });
+        }
+
+        let output = self.transport.output(command, &[])?;
+        if output.code != 0 {
+            if self.quiet {
+                io::stderr().write_all(&output.stdout)?;
+            } else {
+                io::stdout().write_all(&output.stdout)?;
             }
+            io::stderr().write_all(&output.stderr)?;
+            return Err(remote_error(command, output.code));
+        }
 
-            // Disable strict host key check
-            // ssh_cmd.arg("-o").arg("StrictHostKeyChecking=no");
+        Ok(())
+    }
 
-            // Add port forwarding arguments
-            info!("Port forwarding: {}", forward_ports.join(", "));
-            for arg in ssh_forward_args {
-                ssh_cmd.arg(&arg);
-            }
+    /// Run a command on the remote host, handing its stdout to `handle_stdout` while
+    /// stderr is passed on with remote project paths rewritten
+    fn run_ssh_streaming<T>(
+        &self,
+        command: &str,
+        forward_ports: &[Forward],
+        handle_stdout: impl FnOnce(Box<dyn Read + Send>) -> io::Result<T>,
+    ) -> Result<T, Box<dyn std::error::Error>> {
+        let options = ExecOptions {
+            tty: !self.quiet,
+            interactive: true,
+            forwards: forward_ports,
+        };
+        let mut child = self.transport.spawn(command, &options)?;
+        let stdout = child.stdout.take().expect("piped stdout");
+        let stderr = child.stderr.take().expect("piped stderr");
+
+        let (result, code) = std::thread::scope(|scope| {
+            scope.spawn(|| self.path_rewriter(false).pump(stderr, io::stderr()));
+            let result = handle_stdout(stdout);
+            (result, child.wait())
+        });
+
+        let code = code?;
+        if code != 0 {
+            return Err(remote_error(command, code));
         }
 
-        ssh_cmd
-            .arg("-p")
-            .arg(self.port.to_string())
-            .arg(&self.host)
-            .arg(command);
+        Ok(result?)
+    }
 
-        if print_output {
-            ssh_cmd.stdout(Stdio::inherit()).stderr(Stdio::inherit());
-            let status = ssh_cmd.status()?;
-            if !status.success() {
-                return Err(format!("SSH command failed: {}", command).into());
-            }
-        } else {
-            let output = ssh_cmd.output()?;
-            let status = output.status;
-            if !status.success() {
-                io::stdout().write_all(&output.stdout)?;
-                io::stderr().write_all(&output.stderr)?;
-                return Err(format!("SSH command failed: {}", command).into());
-            }
+    /// Run a command on the remote host with `input` as its stdin
+    fn run_ssh_input(&self, command: &str, input: &[u8]) -> Result<(), Box<dyn std::error::Error>> {
+        let output = self.transport.output(command, input)?;
+        if output.code != 0 {
+            io::stderr().write_all(&output.stderr)?;
+            return Err(remote_error(command, output.code));
         }
 
         Ok(())
     }
+
+    /// Run a command on the remote host and return its stdout
+    fn run_ssh_output(&self, command: &str) -> Result<String, Box<dyn std::error::Error>> {
+        let output = self.transport.output(command, &[])?;
+        if output.code != 0 {
+            io::stderr().write_all(&output.stderr)?;
+            return Err(remote_error(command, output.code));
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+}
+
+/// Report a remote command that failed while syncing or copying artifacts as a failure of
+/// that step (`kind` is `OffloadError::Sync` or `OffloadError::Artifacts`). Connection
+/// failures are passed on as they are.
+fn step_error(
+    kind: fn(String) -> OffloadError,
+    what: &str,
+) -> impl Fn(Box<dyn std::error::Error>) -> Box<dyn std::error::Error> + '_ {
+    move |e| match e.downcast_ref::<OffloadError>() {
+        Some(OffloadError::Remote { code, .. }) => {
+            kind(format!("{} (exit code {})", what, code)).into()
+        }
+        _ => e,
+    }
+}
+
+fn remote_error(command: &str, code: i32) -> Box<dyn std::error::Error> {
+    OffloadError::Remote {
+        command: command.to_string(),
+        code,
+    }
+    .into()
 }
@@ -0,0 +1,286 @@
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use log::{debug, info, warn};
+
+use crate::config::HostConfig;
+use crate::error::OffloadError;
+use crate::transport;
+use crate::util::shell_quote;
+
+/// Hosts with less free space than this in their remote directory are only used as a last resort
+pub const DEFAULT_MIN_FREE_MB: u64 = 1024;
+
+/// How long to wait for a pool host to accept the SSH connection
+const PROBE_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Records the pool host the project was last built on, relative to the project root. It
+/// lives next to the copied artifacts and goes away with them on `offload clean`.
+const LAST_HOST_FILE: &str = "target/offload/pool-host";
+
+/// Load and capacity of a build host as reported by a quick SSH probe
+#[derive(Debug, Clone)]
+struct Probe {
+    load: f64,
+    cpus: u32,
+    free_mb: u64,
+    latency: Duration,
+}
+
+impl Probe {
+    /// Parse the output of the probe script: the load average line, the CPU count and the
+    /// last line of `df -Pk`
+    fn parse(output: &str, latency: Duration) -> Result<Probe> {
+        let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+
+        let load = lines
+            .next()
+            .and_then(|line| line.split_whitespace().next())
+            .and_then(|load| load.parse::<f64>().ok())
+            .context("Cannot parse load average")?;
+        let cpus = lines
+            .next()
+            .and_then(|line| line.parse::<u32>().ok())
+            .context("Cannot parse CPU count")?;
+        // Filesystem 1024-blocks Used Available Capacity Mounted on
+        let free_kb = lines
+            .next()
+            .and_then(|line| line.split_whitespace().nth(3))
+            .and_then(|free| free.parse::<u64>().ok())
+            .context("Cannot parse free disk space")?;
+
+        Ok(Probe {
+            load,
+            cpus,
+            free_mb: free_kb / 1024,
+            latency,
+        })
+    }
+
+    /// Lower is better. The 1 minute load average per CPU dominates, with every
+    /// second of connection latency weighing as much as a fully loaded CPU.
+    fn score(&self) -> f64 {
+        self.load / f64::from(self.cpus.max(1)) + self.latency.as_secs_f64()
+    }
+}
+
+/// Pick the least busy reachable host out of `candidates`
+pub fn select_host(
+    candidates: Vec<HostConfig>,
+    min_free_mb: u64,
+) -> Result<HostConfig, Box<dyn std::error::Error>> {
+    if candidates.len() <= 1 {
+        return Ok(candidates.into_iter().next().ok_or("Host pool is empty")?);
+    }
+
+    debug!("Probing {} pool hosts...", candidates.len());
+
+    let probes: Vec<Result<Probe>> = std::thread::scope(|scope| {
+        let handles = candidates
+            .iter()
+            .map(|host| scope.spawn(move || probe(host)))
+            .collect::<Vec<_>>();
+
+        handles
+            .into_iter()
+            .map(|handle| {
+                handle
+                    .join()
+                    .unwrap_or_else(|_| bail!("Host probe panicked"))
+            })
+            .collect()
+    });
+
+    let mut reachable = Vec::new();
+    for (host, probe) in candidates.into_iter().zip(probes) {
+        match probe {
+            Ok(probe) => {
+                debug!(
+                    "{}: load {:.2} on {} CPUs, {} MiB free, {} ms",
+                    host.display_name(),
+                    probe.load,
+                    probe.cpus,
+                    probe.free_mb,
+                    probe.latency.as_millis()
+                );
+                reachable.push((host, probe));
+            }
+            Err(e) => warn!("Skipping unreachable host {}: {:#}", host.display_name(), e),
+        }
+    }
+
+    let (host, probe) = choose(reachable, min_free_mb).ok_or_else(|| {
+        OffloadError::Transport("None of the hosts in the pool are reachable".to_string())
+    })?;
+
+    info!(
+        "Selected build host {} (load {:.2} on {} CPUs, {} MiB free, {} ms)",
+        host.display_name(),
+        probe.load,
+        probe.cpus,
+        probe.free_mb,
+        probe.latency.as_millis()
+    );
+
+    Ok(host)
+}
+
+fn probe(host: &HostConfig) -> Result<Probe> {
+    let base = shell_quote(&host.remote_base_dir);
+    let script = format!(
+        "mkdir -p {base} && \
+         (cat /proc/loadavg 2>/dev/null || sysctl -n vm.loadavg | tr -d '{{}}') && \
+         (nproc 2>/dev/null || sysctl -n hw.ncpu) && \
+         df -Pk {base} | tail -n 1"
+    );
+
+    let start = Instant::now();
+    let output = transport::connect(host, Some(PROBE_CONNECT_TIMEOUT))?.output(&script, &[])?;
+    let latency = start.elapsed();
+
+    if output.code != 0 {
+        bail!("{}", String::from_utf8_lossy(&output.stderr).trim());
+    }
+
+    Probe::parse(&String::from_utf8_lossy(&output.stdout), latency)
+}
+
+/// The best scored of the `reachable` hosts with at least `min_free_mb` of free space, or
+/// of all of them if none has enough. `None` if no host is reachable.
+fn choose<T>(mut reachable: Vec<(T, Probe)>, min_free_mb: u64) -> Option<(T, Probe)> {
+    let has_space = reachable
+        .iter()
+        .any(|(_, probe)| probe.free_mb >= min_free_mb);
+    if has_space {
+        reachable.retain(|(_, probe)| probe.free_mb >= min_free_mb);
+    } else if !reachable.is_empty() {
+        warn!(
+            "No pool host has at least {} MiB of free disk space, picking the least busy one",
+            min_free_mb
+        );
+    }
+
+    // `min_by` keeps the first of equally scored hosts, so ties go to the pool order
+    reachable
+        .into_iter()
+        .min_by(|(_, a), (_, b)| a.score().total_cmp(&b.score()))
+}
+
+/// The pool host the project at `project_root` was last built on, if it is still among
+/// `candidates`
+pub fn last_host(project_root: &Path, candidates: &[HostConfig]) -> Option<HostConfig> {
+    let recorded = fs::read_to_string(project_root.join(LAST_HOST_FILE)).ok()?;
+    candidates
+        .iter()
+        .find(|host| host.display_name() == recorded.trim())
+        .cloned()
+}
+
+/// Remember `host` as the one the project at `project_root` is built on
+pub fn record_host(project_root: &Path, host: &HostConfig) {
+    let path = project_root.join(LAST_HOST_FILE);
+    let written = path
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&path, format!("{}\n", host.display_name())));
+    if let Err(e) = written {
+        warn!("Cannot record the build host in {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn probe(load: f64, cpus: u32, free_mb: u64, latency_ms: u64) -> Probe {
+        Probe {
+            load,
+            cpus,
+            free_mb,
+            latency: Duration::from_millis(latency_ms),
+        }
+    }
+
+    fn chosen(reachable: Vec<(&'static str, Probe)>, min_free_mb: u64) -> Option<&'static str> {
+        choose(reachable, min_free_mb).map(|(name, _)| name)
+    }
+
+    #[test]
+    fn parses_linux_probe() {
+        let output = "0.52 0.58 0.59 2/1021 12345\n\
+                      16\n\
+                      /dev/nvme0n1p2 490617784 212062392 253560756  46% /\n";
+        let probe = Probe::parse(output, Duration::from_millis(30)).unwrap();
+        assert_eq!(probe.load, 0.52);
+        assert_eq!(probe.cpus, 16);
+        assert_eq!(probe.free_mb, 253560756 / 1024);
+        assert_eq!(probe.latency, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn parses_bsd_probe() {
+        // `sysctl -n vm.loadavg` with the braces removed
+        let output = " 1.50 1.20 1.10 \n8\n/dev/disk3s5  971350180 483105848 467913032    51%    /System/Volumes/Data\n";
+        let probe = Probe::parse(output, Duration::ZERO).unwrap();
+        assert_eq!(probe.load, 1.5);
+        assert_eq!(probe.cpus, 8);
+        assert_eq!(probe.free_mb, 467913032 / 1024);
+    }
+
+    #[test]
+    fn rejects_incomplete_probe() {
+        let error = Probe::parse("0.5 0.5 0.5\nmany\n", Duration::ZERO).unwrap_err();
+        assert_eq!(error.to_string(), "Cannot parse CPU count");
+        let error = Probe::parse("0.5\n4\n", Duration::ZERO).unwrap_err();
+        assert_eq!(error.to_string(), "Cannot parse free disk space");
+        assert!(Probe::parse("", Duration::ZERO).is_err());
+    }
+
+    #[test]
+    fn score_is_load_per_cpu_plus_latency() {
+        assert_eq!(probe(4.0, 8, 0, 0).score(), 0.5);
+        assert_eq!(probe(4.0, 8, 0, 250).score(), 0.75);
+        // A CPU count of 0 doesn't divide by zero
+        assert_eq!(probe(2.0, 0, 0, 0).score(), 2.0);
+    }
+
+    #[test]
+    fn chooses_least_loaded_host() {
+        let reachable = vec![
+            ("busy", probe(8.0, 8, 5000, 10)),
+            ("idle", probe(1.0, 8, 5000, 10)),
+            ("far", probe(0.0, 8, 5000, 2000)),
+        ];
+        assert_eq!(chosen(reachable, 1024), Some("idle"));
+    }
+
+    #[test]
+    fn ties_go_to_pool_order() {
+        let reachable = vec![
+            ("first", probe(1.0, 4, 5000, 0)),
+            ("second", probe(1.0, 4, 5000, 0)),
+        ];
+        assert_eq!(chosen(reachable, 1024), Some("first"));
+    }
+
+    #[test]
+    fn skips_hosts_without_space() {
+        let reachable = vec![
+            ("full", probe(0.0, 8, 100, 0)),
+            ("roomy", probe(6.0, 8, 5000, 0)),
+        ];
+        assert_eq!(chosen(reachable, 1024), Some("roomy"));
+    }
+
+    #[test]
+    fn falls_back_to_least_busy_when_all_are_full() {
+        let reachable = vec![
+            ("busy", probe(6.0, 8, 100, 0)),
+            ("idle", probe(0.5, 8, 200, 0)),
+        ];
+        assert_eq!(chosen(reachable, 1024), Some("idle"));
+        assert_eq!(chosen(Vec::new(), 1024), None);
+    }
+}
@@ -0,0 +1,191 @@
+use std::io::{self, Read, Write};
+
+/// Replaces path prefixes in a byte stream, e.g. remote project paths in compiler
+/// output with the corresponding local paths.
+///
+/// Data is passed on as soon as it arrives; only a trailing fragment that could be
+/// the start of a path to replace is held back until the next chunk decides it.
+pub struct PathRewriter {
+    replacements: Vec<(Vec<u8>, Vec<u8>)>,
+    pending: Vec<u8>,
+}
+
+impl PathRewriter {
+    pub fn new(replacements: Vec<(String, String)>) -> Self {
+        let mut replacements: Vec<(Vec<u8>, Vec<u8>)> = replacements
+            .into_iter()
+            .filter(|(from, _)| !from.is_empty())
+            .map(|(from, to)| (from.into_bytes(), to.into_bytes()))
+            .collect();
+
+        // Prefer the longest match when one path is nested in another
+        replacements.sort_by_key(|(from, _)| std::cmp::Reverse(from.len()));
+
+        PathRewriter {
+            replacements,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Rewrite the next chunk of the stream, returning the bytes that are ready to be written
+    pub fn feed(&mut self, data: &[u8]) -> Vec<u8> {
+        self.pending.extend_from_slice(data);
+        self.rewrite(false)
+    }
+
+    /// Flush whatever is still held back at the end of the stream
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.rewrite(true)
+    }
+
+    /// Rewrite the pending bytes. Unless the stream ends, a fragment at the end that may
+    /// still turn into a (longer) match is kept pending.
+    fn rewrite(&mut self, at_end: bool) -> Vec<u8> {
+        let buffer = std::mem::take(&mut self.pending);
+
+        let mut output = Vec::with_capacity(buffer.len());
+        let mut pos = 0;
+        'scan: while pos < buffer.len() {
+            let rest = &buffer[pos..];
+            // Longest first, so a longer match that is still incomplete wins over a
+            // shorter one that is already complete
+            for (from, to) in &self.replacements {
+                if rest.starts_with(from) {
+                    output.extend_from_slice(to);
+                    pos += from.len();
+                    continue 'scan;
+                }
+                if !at_end && rest.len() < from.len() && from.starts_with(rest) {
+                    self.pending = rest.to_vec();
+                    return output;
+                }
+            }
+
+            output.push(buffer[pos]);
+            pos += 1;
+        }
+
+        output
+    }
+
+    /// Copy `reader` to `writer`, rewriting paths on the way
+    pub fn pump(mut self, mut reader: impl Read, mut writer: impl Write) -> io::Result<()> {
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            };
+
+            writer.write_all(&self.feed(&buf[..n]))?;
+            writer.flush()?;
+        }
+
+        writer.write_all(&self.finish())?;
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_rewriter() -> PathRewriter {
+        PathRewriter::new(vec![
+            ("/remote/proj".to_string(), "/home/me/proj".to_string()),
+            ("/remote/proj/dep".to_string(), "/home/me/dep".to_string()),
+        ])
+    }
+
+    /// Feed `chunks` one after the other and collect everything written
+    fn rewrite(chunks: &[&str]) -> String {
+        let mut rewriter = new_rewriter();
+        let mut output = Vec::new();
+        for chunk in chunks {
+            output.extend(rewriter.feed(chunk.as_bytes()));
+        }
+        output.extend(rewriter.finish());
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn replaces_within_a_chunk() {
+        assert_eq!(
+            rewrite(&["error at /remote/proj/src/main.rs:1\n"]),
+            "error at /home/me/proj/src/main.rs:1\n"
+        );
+    }
+
+    #[test]
+    fn prefers_longest_match() {
+        assert_eq!(
+            rewrite(&["/remote/proj/dep/lib.rs /remote/proj/src"]),
+            "/home/me/dep/lib.rs /home/me/proj/src"
+        );
+    }
+
+    #[test]
+    fn replaces_across_chunks() {
+        let text = "Compiling app (/remote/proj/dep) and /remote/proj\n";
+        let expected = "Compiling app (/home/me/dep) and /home/me/proj\n";
+        // Every possible split point, including inside both paths
+        for split in 0..=text.len() {
+            let (first, second) = text.split_at(split);
+            assert_eq!(rewrite(&[first, second]), expected, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn replaces_byte_by_byte() {
+        let text = "/remote/proj/src /remote/proj/dep";
+        let chunks: Vec<&str> = (0..text.len()).map(|i| &text[i..i + 1]).collect();
+        assert_eq!(rewrite(&chunks), "/home/me/proj/src /home/me/dep");
+    }
+
+    #[test]
+    fn holds_back_only_possible_matches() {
+        let mut rewriter = new_rewriter();
+        assert_eq!(rewriter.feed(b"path /rem"), b"path ");
+        // Turns out not to be a match after all
+        assert_eq!(rewriter.feed(b"ote/other"), b"/remote/other");
+        assert_eq!(rewriter.feed(b" /remote/pr"), b" ");
+        assert_eq!(rewriter.finish(), b"/remote/pr");
+    }
+
+    #[test]
+    fn finish_flushes_prefix_of_longer_match() {
+        // `/remote/proj` is complete but could still grow into `/remote/proj/dep`
+        let mut rewriter = new_rewriter();
+        assert_eq!(rewriter.feed(b"at /remote/proj/d"), b"at ");
+        assert_eq!(rewriter.feed(b"ep"), b"/home/me/dep");
+
+        let mut rewriter = new_rewriter();
+        assert_eq!(rewriter.feed(b"at /remote/proj/d"), b"at ");
+        assert_eq!(rewriter.feed(b"oc"), b"/home/me/proj/doc");
+
+        let mut rewriter = new_rewriter();
+        assert_eq!(rewriter.feed(b"at /remote/proj"), b"at ");
+        assert_eq!(rewriter.finish(), b"/home/me/proj");
+    }
+
+    #[test]
+    fn leaves_invalid_utf8_alone() {
+        let mut rewriter = new_rewriter();
+        let mut output = rewriter.feed(b"\xff/remote/proj\xfe");
+        output.extend(rewriter.finish());
+        assert_eq!(output, b"\xff/home/me/proj\xfe");
+    }
+
+    #[test]
+    fn pump_copies_everything() {
+        let input = "a /remote/proj/x\nb /remote/proj/dep/y\n".repeat(1000);
+        let mut output = Vec::new();
+        new_rewriter().pump(input.as_bytes(), &mut output).unwrap();
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "a /home/me/proj/x\nb /home/me/dep/y\n".repeat(1000)
+        );
+    }
+}
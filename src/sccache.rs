@@ -0,0 +1,306 @@
+use crate::config::{HostConfig, SccacheConfig};
+use crate::util::{shell_quote, stable_hash};
+
+/// Name of the `RUSTC_WRAPPER` script in the cache directory
+const WRAPPER_NAME: &str = "offload-rustc-wrapper";
+
+/// Compiles through sccache. Only the offloaded project's crates get `--remap-path-prefix`,
+/// which is part of sccache's cache key, so dependencies are cached independently of the
+/// project and shared with the other projects on the host.
+const WRAPPER_SCRIPT: &str = r#"#!/bin/sh
+# Installed by cargo-offload
+if [ -n "$OFFLOAD_REMAP_FROM" ]; then
+    case "$CARGO_MANIFEST_DIR/" in
+    "$OFFLOAD_REMAP_FROM"/*)
+        exec sccache "$@" "--remap-path-prefix=$OFFLOAD_REMAP_FROM=$OFFLOAD_REMAP_TO" ;;
+    esac
+fi
+exec sccache "$@"
+"#;
+
+/// Output of `setup_command()` when sccache has to be installed first
+pub const MISSING: &str = "missing";
+
+/// Server ports are picked from `PORT_BASE..PORT_BASE + PORT_RANGE`, below the ephemeral
+/// ports Linux hands out for outgoing connections
+const PORT_BASE: u16 = 10000;
+const PORT_RANGE: u16 = 20000;
+
+/// sccache on the build host, with one cache directory for all projects.
+///
+/// Each user and project gets a server of its own on a port derived from the remote
+/// project directory, as the server compiles with its own uid and inside its own container.
+/// Two projects of the same user whose directories map to the same port share a server,
+/// which goes unnoticed: the uid check in `setup_command()` only keeps other users out.
+#[derive(Debug, Clone)]
+pub struct Sccache {
+    pub dir: String,
+    port: u16,
+    cache_size: Option<String>,
+    /// Report hits and misses after each build
+    pub stats: bool,
+}
+
+impl Sccache {
+    /// `None` unless enabled. Without a configured directory the cache goes into the remote
+    /// base directory, shared by all users of the host.
+    ///
+    /// `remote_root` is the remote directory of the project, which is unique per user.
+    pub fn new(config: SccacheConfig, host: &HostConfig, remote_root: &str) -> Option<Sccache> {
+        if !config.enabled.unwrap_or(false) {
+            return None;
+        }
+
+        let dir = host
+            .sccache_dir
+            .clone()
+            .or(config.dir)
+            .unwrap_or_else(|| format!("{}/sccache", host.remote_base_dir));
+
+        let port = PORT_BASE + (stable_hash(remote_root.as_bytes()) % u64::from(PORT_RANGE)) as u16;
+
+        Some(Sccache {
+            dir,
+            port,
+            cache_size: config.cache_size,
+            stats: config.stats.unwrap_or(false),
+        })
+    }
+
+    /// Settings of the sccache server, which are only picked up when it starts
+    pub fn server_env_vars(&self) -> Vec<String> {
+        let mut env_vars = vec![
+            format!("SCCACHE_DIR={}", self.dir),
+            format!("SCCACHE_SERVER_PORT={}", self.port),
+        ];
+        if let Some(size) = &self.cache_size {
+            env_vars.push(format!("SCCACHE_CACHE_SIZE={}", size));
+        }
+        env_vars
+    }
+
+    /// Environment for cargo, remapping paths below `remap = (from, to)` if given
+    pub fn env_vars(&self, remap: Option<(&str, &str)>) -> Vec<String> {
+        let mut env_vars = vec![format!("RUSTC_WRAPPER={}/{}", self.dir, WRAPPER_NAME)];
+        env_vars.extend(self.server_env_vars());
+        if let Some((from, to)) = remap {
+            env_vars.push(format!("OFFLOAD_REMAP_FROM={}", from));
+            env_vars.push(format!("OFFLOAD_REMAP_TO={}", to));
+        }
+        env_vars
+    }
+
+    /// Shell command installing the wrapper script and making sure the sccache server uses
+    /// our cache directory. A server started earlier with other settings, e.g. by a build
+    /// outside of offload, is restarted. Prints `MISSING` if sccache is not installed.
+    ///
+    /// Where `/proc/net/tcp` tells who listens on the server port, a server of another user
+    /// is neither used nor stopped, the command fails instead.
+    pub fn setup_command(&self) -> String {
+        let wrapper = shell_quote(&format!("{}/{}", self.dir, WRAPPER_NAME));
+        let server_env = self
+            .server_env_vars()
+            .iter()
+            .map(|var| format!("export {}; ", shell_quote(var)))
+            .collect::<String>();
+
+        format!(
+            "umask 002; \
+             if [ \"$(cat {wrapper} 2>/dev/null)\" != {script} ]; then \
+             printf '%s\\n' {script} > {wrapper}.$$ && chmod 775 {wrapper}.$$ && \
+             mv {wrapper}.$$ {wrapper} || exit 1; fi; \
+             command -v sccache >/dev/null || {{ echo {missing}; exit 0; }}; \
+             {server_env}\
+             if [ -r /proc/net/tcp ]; then \
+             owner=$(awk -v port={hex_port} '$4 == \"0A\" && substr($2, length($2) - 4) == port {{ print $8; exit }}' /proc/net/tcp); \
+             if [ -n \"$owner\" ] && [ \"$owner\" != \"$(id -u)\" ]; then \
+             echo \"sccache server port {port} is in use by uid $owner\" >&2; exit 1; fi; fi; \
+             if ! sccache --show-stats 2>/dev/null | grep -qF {location}; then \
+             sccache --stop-server >/dev/null 2>&1; sccache --start-server >/dev/null || exit 1; fi",
+            script = shell_quote(WRAPPER_SCRIPT.trim_end()),
+            missing = MISSING,
+            // `local_address` is `<ip>:<port>` in hex, state `0A` is listening
+            hex_port = format_args!(":{:04X}", self.port),
+            port = self.port,
+            // `Cache location  Local disk: "<dir>"`
+            location = shell_quote(&format!("\"{}\"", self.dir)),
+            wrapper = wrapper,
+        )
+    }
+}
+
+/// Counters from `sccache --show-stats`
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Stats {
+    /// Parse the human readable output, which has lines like `Cache hits      42`. The
+    /// per-language breakdowns (`Cache hits (Rust)`) are left out.
+    pub fn parse(output: &str) -> Option<Stats> {
+        let count = |label: &str| {
+            output.lines().find_map(|line| {
+                let value = line.strip_prefix(label)?;
+                value.starts_with(char::is_whitespace).then_some(())?;
+                value.trim().parse::<u64>().ok()
+            })
+        };
+
+        Some(Stats {
+            hits: count("Cache hits")?,
+            misses: count("Cache misses")?,
+        })
+    }
+
+    /// What happened between `before` and `self`. A restarted server starts from zero.
+    pub fn since(self, before: Stats) -> Stats {
+        if self.hits < before.hits || self.misses < before.misses {
+            return self;
+        }
+        Stats {
+            hits: self.hits - before.hits,
+            misses: self.misses - before.misses,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SshClient;
+
+    /// `sccache --show-stats` of sccache 0.8
+    const SHOW_STATS: &str = "\
+Compile requests                    130
+Compile requests executed           112
+Cache hits                           95
+Cache hits (Rust)                    95
+Cache misses                         17
+Cache misses (Rust)                  17
+Cache hits rate                   84.82 %
+Cache hits rate (Rust)            84.82 %
+Cache timeouts                        0
+Cache read errors                     0
+Forced recaches                       0
+Cache write errors                    0
+Compilation failures                  0
+Cache errors                          0
+Non-cacheable compilations            0
+Non-cacheable calls                  18
+Non-compilation calls                 0
+Unsupported compiler calls            0
+Average cache write               0.002 s
+Average compiler                  1.286 s
+Average cache read hit            0.001 s
+Failed distributed compilations       0
+
+Non-cacheable reasons:
+crate-type                           12
+-                                     6
+
+Cache location                  Local disk: \"/srv/offload/sccache\"
+Use direct/preprocessor mode?   yes
+Version (client)                0.8.2
+Cache size                          312 MiB
+Max cache size                       10 GiB
+";
+
+    fn host(sccache_dir: Option<&str>) -> HostConfig {
+        HostConfig {
+            name: None,
+            address: "builder".to_string(),
+            port: 22,
+            identity_file: None,
+            remote_base_dir: "/srv/offload".to_string(),
+            target: None,
+            jobs: None,
+            ssh_client: SshClient::Openssh,
+            control_persist: None,
+            local: false,
+            sccache_dir: sccache_dir.map(str::to_string),
+            cargo_home: None,
+        }
+    }
+
+    fn enabled(dir: Option<&str>) -> SccacheConfig {
+        SccacheConfig {
+            enabled: Some(true),
+            dir: dir.map(str::to_string),
+            ..SccacheConfig::default()
+        }
+    }
+
+    #[test]
+    fn parses_show_stats() {
+        assert_eq!(
+            Stats::parse(SHOW_STATS),
+            Some(Stats {
+                hits: 95,
+                misses: 17
+            })
+        );
+        assert_eq!(Stats::parse("sccache: error: Server startup failed"), None);
+    }
+
+    #[test]
+    fn stats_since_earlier_stats() {
+        let before = Stats {
+            hits: 90,
+            misses: 10,
+        };
+        let after = Stats {
+            hits: 95,
+            misses: 17,
+        };
+        assert_eq!(after.since(before), Stats { hits: 5, misses: 7 });
+
+        // The server was restarted in between
+        let restarted = Stats {
+            hits: 3,
+            misses: 20,
+        };
+        assert_eq!(restarted.since(before), restarted);
+    }
+
+    #[test]
+    fn disabled_by_default() {
+        assert!(Sccache::new(SccacheConfig::default(), &host(None), "/srv/offload/app").is_none());
+    }
+
+    #[test]
+    fn cache_dir_precedence() {
+        let dir = |config_dir, host_dir| {
+            Sccache::new(enabled(config_dir), &host(host_dir), "/srv/offload/app")
+                .unwrap()
+                .dir
+        };
+        assert_eq!(dir(None, None), "/srv/offload/sccache");
+        assert_eq!(dir(Some("/cache"), None), "/cache");
+        assert_eq!(dir(Some("/cache"), Some("/fast/cache")), "/fast/cache");
+    }
+
+    #[test]
+    fn server_port_depends_on_the_project() {
+        let port = |remote_root| {
+            Sccache::new(enabled(None), &host(None), remote_root)
+                .unwrap()
+                .port
+        };
+        assert_eq!(port("/srv/offload/app"), port("/srv/offload/app"));
+        assert_ne!(port("/srv/offload/app"), port("/srv/offload/other"));
+        for root in ["", "/srv/offload/app", "/home/user/.offload/x"] {
+            assert!((PORT_BASE..PORT_BASE + PORT_RANGE).contains(&port(root)));
+        }
+
+        let sccache = Sccache::new(enabled(None), &host(None), "/srv/offload/app").unwrap();
+        assert_eq!(
+            sccache.server_env_vars(),
+            [
+                "SCCACHE_DIR=/srv/offload/sccache".to_string(),
+                format!("SCCACHE_SERVER_PORT={}", sccache.port),
+            ]
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use ignore::WalkBuilder;
+use log::debug;
+
+/// Per-directory ignore file with gitignore syntax; `!pattern` syncs files git ignores
+pub const OFFLOAD_IGNORE_FILE: &str = ".offloadignore";
+
+/// rsync exclude patterns that always apply
+pub const DEFAULT_EXCLUDES: &[&str] = &["target/", ".git/", "*.swp", "*.tmp", ".cargo/"];
+
+/// Whether an entry is covered by `DEFAULT_EXCLUDES`, so walking it can be skipped
+fn is_default_excluded(name: &str, is_dir: bool) -> bool {
+    if is_dir {
+        matches!(name, "target" | ".git" | ".cargo")
+    } else {
+        name.ends_with(".swp") || name.ends_with(".tmp")
+    }
+}
+
+/// Walks the files below `root` that are synced: everything not covered by
+/// `DEFAULT_EXCLUDES`, `.offloadignore` files and, if `gitignore` is set, git's ignore files
+pub fn walker(root: &Path, gitignore: bool) -> WalkBuilder {
+    let mut builder = WalkBuilder::new(root);
+    builder
+        .hidden(false)
+        .ignore(false)
+        .git_ignore(gitignore)
+        .git_exclude(gitignore)
+        .git_global(gitignore)
+        .require_git(false)
+        .add_custom_ignore_filename(OFFLOAD_IGNORE_FILE)
+        .filter_entry(|entry| {
+            let is_dir = entry.file_type().is_some_and(|t| t.is_dir());
+            !is_default_excluded(&entry.file_name().to_string_lossy(), is_dir)
+        });
+    builder
+}
+
+/// rsync exclude rules for everything below `root` that is ignored by `.gitignore` files
+/// (including `.git/info/exclude` and the global excludes file, if `gitignore` is set) or
+/// by `.offloadignore` files.
+///
+/// The ignore files are evaluated with git's semantics and turned into one rule anchored
+/// at the transfer root per ignored file or directory. Only the topmost ignored entries
+/// are listed, so ignored directories are neither walked nor sent.
+pub fn exclude_rules(root: &Path, gitignore: bool) -> Result<Vec<String>> {
+    let walker = walker(root, gitignore).build();
+
+    let mut included: HashSet<PathBuf> = HashSet::new();
+    for entry in walker {
+        let entry = entry.with_context(|| format!("Cannot walk {}", root.display()))?;
+        included.insert(entry.into_path());
+    }
+
+    let mut rules = Vec::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries =
+            fs::read_dir(&dir).with_context(|| format!("Cannot read {}", dir.display()))?;
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+            let is_dir = entry.file_type()?.is_dir();
+
+            if included.contains(&path) {
+                if is_dir {
+                    dirs.push(path);
+                }
+                continue;
+            }
+            if is_default_excluded(&entry.file_name().to_string_lossy(), is_dir) {
+                continue;
+            }
+
+            let relative = path.strip_prefix(root)?.to_string_lossy();
+            let rule = format!(
+                "/{}{}",
+                escape_pattern(&relative),
+                if is_dir { "/" } else { "" }
+            );
+            debug!("Ignoring {}", rule);
+            rules.push(rule);
+        }
+    }
+
+    rules.sort();
+    Ok(rules)
+}
+
+/// rsync only treats backslashes as escapes in patterns that contain wildcards
+fn escape_pattern(path: &str) -> String {
+    if !path.contains(['*', '?', '[']) {
+        return path.to_string();
+    }
+
+    let mut escaped = String::with_capacity(path.len());
+    for c in path.chars() {
+        if matches!(c, '*' | '?' | '[' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::temp_dir;
+
+    /// A project with ignored files, nested ignored directories and default excludes
+    fn project(name: &str) -> PathBuf {
+        let root = temp_dir(name);
+        let files = [
+            (".gitignore", "*.log\ngenerated/\n"),
+            (OFFLOAD_IGNORE_FILE, "!keep.log\nsecrets/\n"),
+            ("src/main.rs", ""),
+            ("build.log", ""),
+            ("keep.log", ""),
+            ("odd[1].log", ""),
+            ("src/nested/trace.log", ""),
+            ("generated/deep/file.rs", ""),
+            ("secrets/key", ""),
+            ("target/debug/app", ""),
+            ("notes.swp", ""),
+        ];
+        for (path, content) in files {
+            let path = root.join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, content).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn rules_cover_topmost_ignored_entries() {
+        let root = project("filter-gitignore");
+        let rules = exclude_rules(&root, true);
+        fs::remove_dir_all(&root).unwrap();
+
+        // `keep.log` is synced although git ignores it, default excludes need no rule
+        assert_eq!(
+            rules.unwrap(),
+            [
+                "/build.log",
+                "/generated/",
+                "/odd\\[1].log",
+                "/secrets/",
+                "/src/nested/trace.log",
+            ]
+        );
+    }
+
+    #[test]
+    fn gitignore_can_be_left_out() {
+        let root = project("filter-offloadignore");
+        let rules = exclude_rules(&root, false);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(rules.unwrap(), ["/secrets/"]);
+    }
+
+    #[test]
+    fn patterns_are_only_escaped_with_wildcards() {
+        assert_eq!(escape_pattern("src/main.rs"), "src/main.rs");
+        assert_eq!(escape_pattern("back\\slash"), "back\\slash");
+        assert_eq!(escape_pattern("a*b"), "a\\*b");
+        assert_eq!(escape_pattern("what?\\[x]"), "what\\?\\\\\\[x]");
+    }
+}
@@ -0,0 +1,414 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use crate::error::OffloadError;
+use crate::transport::{ExecOptions, Transport};
+use crate::util::{output_with_input, rsync_error, shell_join, shell_quote};
+
+/// What a sync copies from the source directory
+#[derive(Debug, Clone, Copy)]
+pub enum Selection<'a> {
+    /// The whole directory, removing what the source doesn't have. `rules` are rsync
+    /// exclude rules anchored at the source directory, one per line.
+    Mirror { rules: &'a str },
+    /// The listed files, relative to the source directory
+    Files(&'a [String]),
+    /// The listed files or directories, replacing what the destination has there
+    Trees(&'a [String]),
+}
+
+/// Files to copy between a directory on this machine and one on the build host
+#[derive(Debug, Clone, Copy)]
+pub struct SyncRequest<'a> {
+    pub local_dir: &'a Path,
+    pub remote_dir: &'a str,
+    pub selection: Selection<'a>,
+    /// Patterns to leave out, in rsync's `--exclude` syntax
+    pub excludes: &'a [String],
+    /// Use rsync if the transport supports it, otherwise a tar stream
+    pub rsync: bool,
+    /// rsync's progress option, `None` keeps stdout free of rsync output
+    pub progress: Option<&'a str>,
+}
+
+/// A change `sync_up` would make on the host
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Change {
+    Send { path: String, size: u64 },
+    Delete { path: String },
+}
+
+/// rsync, set up to reach the host's paths the way `transport` says
+fn rsync_command<T: Transport + ?Sized>(transport: &T) -> Option<(Command, String)> {
+    let remote = transport.rsync_remote()?;
+    let mut rsync_cmd = Command::new("rsync");
+    if let Some(shell) = remote.shell {
+        rsync_cmd.arg("-e").arg(shell);
+    }
+    rsync_cmd.arg("-a").arg("--compress");
+    Some((rsync_cmd, remote.prefix))
+}
+
+fn progress_stdio(request: &SyncRequest) -> Stdio {
+    if request.progress.is_some() {
+        Stdio::inherit()
+    } else {
+        Stdio::from(io::stderr())
+    }
+}
+
+/// Run rsync with `input` on its stdin, failures being reported as `error`
+fn run_rsync(
+    rsync_cmd: &mut Command,
+    input: &[u8],
+    error: fn(String) -> OffloadError,
+) -> Result<Vec<u8>, OffloadError> {
+    let output = output_with_input(rsync_cmd, input).map_err(|e| error(rsync_error(e)))?;
+    if !output.status.success() {
+        return Err(error(format!("rsync {}", output.status)));
+    }
+    Ok(output.stdout)
+}
+
+/// Copy files from this machine to the host
+pub fn sync_up<T: Transport + ?Sized>(
+    transport: &T,
+    request: &SyncRequest,
+) -> Result<(), OffloadError> {
+    if let Some((mut rsync_cmd, prefix)) = rsync_command(transport).filter(|_| request.rsync) {
+        let input = rsync_up_args(&mut rsync_cmd, &prefix, request);
+        rsync_cmd
+            .stdout(progress_stdio(request))
+            .stderr(Stdio::inherit());
+        run_rsync(&mut rsync_cmd, &input, OffloadError::Sync)?;
+        return Ok(());
+    }
+
+    let paths = match request.selection {
+        Selection::Files(paths) | Selection::Trees(paths) => paths,
+        Selection::Mirror { .. } => {
+            return Err(OffloadError::Sync(
+                "mirroring a directory requires rsync".to_string(),
+            ))
+        }
+    };
+    if paths.is_empty() {
+        return Ok(());
+    }
+
+    let mut list = Vec::new();
+    for path in paths {
+        list.extend_from_slice(path.as_bytes());
+        list.push(0);
+    }
+    let mut tar_cmd = Command::new("tar");
+    tar_cmd
+        .arg("-C")
+        .arg(request.local_dir)
+        .arg("-czf")
+        .arg("-");
+    for pattern in request.excludes {
+        tar_cmd.arg(format!("--exclude={}", pattern));
+    }
+    tar_cmd.args(["--null", "-T", "-"]);
+
+    // tar keeps whole seconds of the modification time, which can be older than the last
+    // build for a file changed right after it; `-m` makes cargo see the sent files as changed
+    let remote_dir = shell_quote(request.remote_dir);
+    pipe_to_remote(
+        transport,
+        &mut tar_cmd,
+        &list,
+        &format!("mkdir -p {0} && tar -xzmf - -C {0}", remote_dir),
+    )
+}
+
+/// Add sources, destination and options for `sync_up` to `rsync_cmd`, returning its input
+fn rsync_up_args(rsync_cmd: &mut Command, prefix: &str, request: &SyncRequest) -> Vec<u8> {
+    for pattern in request.excludes {
+        rsync_cmd.arg(format!("--exclude={}", pattern));
+    }
+    if let Some(progress) = request.progress {
+        rsync_cmd.arg(progress);
+    }
+
+    let local = format!("{}/", request.local_dir.display());
+    let remote = format!("{}{}/", prefix, request.remote_dir);
+    match request.selection {
+        Selection::Mirror { rules } => {
+            rsync_cmd
+                .arg("--delete")
+                .arg("--exclude-from=-")
+                .arg(local)
+                .arg(remote);
+            rules.as_bytes().to_vec()
+        }
+        Selection::Files(paths) | Selection::Trees(paths) => {
+            if matches!(request.selection, Selection::Trees(_)) {
+                rsync_cmd.arg("--recursive");
+            }
+            rsync_cmd
+                .arg("--files-from=-")
+                .arg("--from0")
+                .arg(local)
+                .arg(remote);
+            let mut list = Vec::new();
+            for path in paths {
+                list.extend_from_slice(path.as_bytes());
+                list.push(0);
+            }
+            list
+        }
+    }
+}
+
+/// What `sync_up` would change on the host, as reported by rsync
+pub fn sync_up_dry_run<T: Transport + ?Sized>(
+    transport: &T,
+    request: &SyncRequest,
+) -> Result<Vec<Change>, OffloadError> {
+    let (mut rsync_cmd, prefix) = rsync_command(transport)
+        .ok_or_else(|| OffloadError::Sync("a dry run requires rsync".to_string()))?;
+    rsync_cmd.arg("--dry-run").arg("--out-format=%i %l %n");
+    let request = SyncRequest {
+        progress: None,
+        ..*request
+    };
+    let input = rsync_up_args(&mut rsync_cmd, &prefix, &request);
+    rsync_cmd.stdout(Stdio::piped()).stderr(Stdio::inherit());
+    let output = run_rsync(&mut rsync_cmd, &input, OffloadError::Sync)?;
+
+    // `<f+++++++++ 1234 src/main.rs` for sent files, `*deleting src/old.rs` for deletions
+    let mut changes = Vec::new();
+    for line in String::from_utf8_lossy(&output).lines() {
+        if let Some(path) = line.strip_prefix("*deleting") {
+            changes.push(Change::Delete {
+                path: path.trim().to_string(),
+            });
+            continue;
+        }
+
+        let mut fields = line.splitn(3, ' ');
+        let (Some(item), Some(size), Some(path)) = (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        if item.starts_with("<f") {
+            changes.push(Change::Send {
+                path: path.to_string(),
+                size: size.parse().unwrap_or(0),
+            });
+        }
+    }
+
+    Ok(changes)
+}
+
+/// Copy files from the host to this machine
+pub fn sync_down<T: Transport + ?Sized>(
+    transport: &T,
+    request: &SyncRequest,
+) -> Result<(), OffloadError> {
+    let artifacts_error = |e: io::Error| OffloadError::Artifacts(e.to_string());
+    fs::create_dir_all(request.local_dir).map_err(artifacts_error)?;
+
+    if let Some((mut rsync_cmd, prefix)) = rsync_command(transport).filter(|_| request.rsync) {
+        for pattern in request.excludes {
+            rsync_cmd.arg(format!("--exclude={}", pattern));
+        }
+        if let Some(progress) = request.progress {
+            rsync_cmd.arg(progress);
+        }
+
+        let remote = format!("{}{}/", prefix, request.remote_dir);
+        let mut input = Vec::new();
+        match request.selection {
+            Selection::Mirror { rules } => {
+                rsync_cmd
+                    .arg("--delete")
+                    .arg("--exclude-from=-")
+                    .arg(remote);
+                input.extend_from_slice(rules.as_bytes());
+            }
+            // Exactly the listed files, read from stdin relative to the remote directory
+            Selection::Files(paths) => {
+                rsync_cmd.arg("--files-from=-").arg("--from0").arg(remote);
+                for path in paths {
+                    input.extend_from_slice(path.as_bytes());
+                    input.push(0);
+                }
+            }
+            // `/./` marks where the path recreated below the destination starts
+            Selection::Trees(paths) => {
+                rsync_cmd.arg("--relative").arg("--delete");
+                for path in paths {
+                    rsync_cmd.arg(format!(
+                        "{}{}/./{}",
+                        prefix,
+                        request.remote_dir,
+                        path.trim_end_matches('/')
+                    ));
+                }
+            }
+        }
+        rsync_cmd
+            .arg(format!("{}/", request.local_dir.display()))
+            .stdout(progress_stdio(request))
+            .stderr(Stdio::inherit());
+        run_rsync(&mut rsync_cmd, &input, OffloadError::Artifacts)?;
+        return Ok(());
+    }
+
+    // Replaced like `rsync --delete` does by starting from an empty directory
+    let mut tar_args: Vec<String> = request
+        .excludes
+        .iter()
+        .map(|pattern| format!("--exclude={}", pattern))
+        .collect();
+    let mut input = Vec::new();
+    match request.selection {
+        Selection::Mirror { .. } => {
+            fs::remove_dir_all(request.local_dir).map_err(artifacts_error)?;
+            fs::create_dir_all(request.local_dir).map_err(artifacts_error)?;
+            tar_args.push(".".to_string());
+        }
+        Selection::Files(paths) => {
+            tar_args.extend(["--null", "-T", "-"].map(String::from));
+            for path in paths {
+                input.extend_from_slice(path.as_bytes());
+                input.push(0);
+            }
+        }
+        Selection::Trees(paths) => {
+            for path in paths {
+                let path = path.trim_end_matches('/');
+                let local_path = request.local_dir.join(path);
+                if local_path.is_dir() {
+                    fs::remove_dir_all(&local_path).map_err(artifacts_error)?;
+                }
+                tar_args.push(path.to_string());
+            }
+        }
+    }
+
+    download_tar(
+        transport,
+        &format!(
+            "cd {} && tar -czf - {}",
+            shell_quote(request.remote_dir),
+            shell_join(&tar_args)
+        ),
+        &input,
+        request.local_dir,
+    )
+}
+
+/// Stream the stdout of the local command `producer`, which is fed `input`, into
+/// `command` on the host. Failures are sync errors.
+pub fn pipe_to_remote<T: Transport + ?Sized>(
+    transport: &T,
+    producer: &mut Command,
+    input: &[u8],
+    command: &str,
+) -> Result<(), OffloadError> {
+    let mut producer = producer
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|e| OffloadError::Sync(format!("cannot execute {:?}: {}", producer, e)))?;
+    let mut producer_stdout = producer.stdout.take().expect("piped stdout");
+
+    let mut remote = transport.spawn(command, &ExecOptions::default())?;
+    let mut remote_stdin = remote.stdin.take().expect("piped stdin");
+    let remote_stdout = remote.stdout.take().expect("piped stdout");
+    let remote_stderr = remote.stderr.take().expect("piped stderr");
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| pass_on(remote_stdout));
+        scope.spawn(|| pass_on(remote_stderr));
+        scope.spawn(move || {
+            let _ = io::copy(&mut producer_stdout, &mut remote_stdin);
+        });
+
+        // Only written once the output is consumed, so the producer cannot block on it
+        if let Some(mut stdin) = producer.stdin.take() {
+            let _ = stdin.write_all(input);
+        }
+    });
+
+    let producer_status = producer
+        .wait()
+        .map_err(|e| OffloadError::Sync(e.to_string()))?;
+    let code = remote.wait()?;
+    if code != 0 {
+        return Err(OffloadError::Sync(format!(
+            "`{}` exited with code {}",
+            command, code
+        )));
+    }
+    if !producer_status.success() {
+        return Err(OffloadError::Sync(format!(
+            "local command {}",
+            producer_status
+        )));
+    }
+
+    Ok(())
+}
+
+/// Unpack the tar stream written by `command` on the host, which is fed `input`, into
+/// `local_dir`. Failures are artifact errors.
+fn download_tar<T: Transport + ?Sized>(
+    transport: &T,
+    command: &str,
+    input: &[u8],
+    local_dir: &Path,
+) -> Result<(), OffloadError> {
+    let mut remote = transport.spawn(command, &ExecOptions::default())?;
+    let mut remote_stdin = remote.stdin.take().expect("piped stdin");
+    let mut remote_stdout = remote.stdout.take().expect("piped stdout");
+    let remote_stderr = remote.stderr.take().expect("piped stderr");
+
+    let mut tar = Command::new("tar")
+        .arg("-xzf")
+        .arg("-")
+        .arg("-C")
+        .arg(local_dir)
+        .stdin(Stdio::piped())
+        .spawn()
+        .map_err(|e| OffloadError::Artifacts(format!("cannot execute tar: {}", e)))?;
+    let mut tar_stdin = tar.stdin.take().expect("piped stdin");
+
+    std::thread::scope(|scope| {
+        scope.spawn(|| pass_on(remote_stderr));
+        scope.spawn(move || {
+            let _ = io::copy(&mut remote_stdout, &mut tar_stdin);
+        });
+
+        let _ = remote_stdin.write_all(input);
+        drop(remote_stdin);
+    });
+
+    let code = remote.wait()?;
+    let tar_status = tar
+        .wait()
+        .map_err(|e| OffloadError::Artifacts(e.to_string()))?;
+    if code != 0 {
+        return Err(OffloadError::Artifacts(format!(
+            "`{}` exited with code {}",
+            command, code
+        )));
+    }
+    if !tar_status.success() {
+        return Err(OffloadError::Artifacts(format!("tar {}", tar_status)));
+    }
+
+    Ok(())
+}
+
+/// Copy output of a remote command to our stderr, keeping stdout for cargo
+fn pass_on(mut reader: impl Read) {
+    let _ = io::copy(&mut reader, &mut io::stderr());
+}
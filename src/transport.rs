@@ -0,0 +1,385 @@
+use std::io::{self, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::time::Duration;
+
+use log::{debug, info};
+
+use crate::config::{HostConfig, SshClient};
+use crate::error::OffloadError;
+use crate::transfer::{self, Change, SyncRequest};
+use crate::util::shell_join;
+
+/// A port forwarded from the local machine to the build host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Forward {
+    pub local: u16,
+    pub remote: u16,
+}
+
+impl Forward {
+    /// Parse `local_port:remote_port`, or just `port` for the same port on both sides
+    pub fn parse(spec: &str) -> Result<Forward, String> {
+        let invalid = || format!("Invalid port forwarding specification: {}", spec);
+        let port = |port: &str| port.parse::<u16>().map_err(|_| invalid());
+
+        let parts: Vec<&str> = spec.split(':').collect();
+        match parts.as_slice() {
+            [port_spec] => {
+                let port = port(port_spec)?;
+                Ok(Forward {
+                    local: port,
+                    remote: port,
+                })
+            }
+            [local, remote] => Ok(Forward {
+                local: port(local)?,
+                remote: port(remote)?,
+            }),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// How a remote command is started
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ExecOptions<'a> {
+    /// Allocate a pseudo-terminal, for interactive programs and progress bars
+    pub tty: bool,
+    /// Pass our stdin on to the command instead of providing a pipe for it
+    pub interactive: bool,
+    pub forwards: &'a [Forward],
+}
+
+/// A command running on the build host, like `std::process::Child`
+pub struct RemoteChild {
+    /// `None` for interactive commands, which read our stdin
+    pub stdin: Option<Box<dyn Write + Send>>,
+    pub stdout: Option<Box<dyn Read + Send>>,
+    pub stderr: Option<Box<dyn Read + Send>>,
+    wait: Box<dyn FnOnce() -> Result<i32, OffloadError> + Send>,
+}
+
+impl RemoteChild {
+    pub fn new(
+        stdin: Option<Box<dyn Write + Send>>,
+        stdout: Box<dyn Read + Send>,
+        stderr: Box<dyn Read + Send>,
+        wait: impl FnOnce() -> Result<i32, OffloadError> + Send + 'static,
+    ) -> Self {
+        RemoteChild {
+            stdin,
+            stdout: Some(stdout),
+            stderr: Some(stderr),
+            wait: Box::new(wait),
+        }
+    }
+
+    /// Close stdin and wait for the command to exit. Returns its exit code, failures of
+    /// the connection itself are `OffloadError::Transport`.
+    pub fn wait(mut self) -> Result<i32, OffloadError> {
+        drop(self.stdin.take());
+        (self.wait)()
+    }
+}
+
+/// Output of a remote command that ran to completion
+pub struct RemoteOutput {
+    pub code: i32,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// How rsync reaches paths on the build host
+pub struct RsyncRemote {
+    /// Value of rsync's `-e` option, `None` if the paths are local
+    pub shell: Option<String>,
+    /// Put in front of paths on the host, e.g. `user@host:`
+    pub prefix: String,
+}
+
+/// How commands and files reach the build host. Ports are forwarded for as long as the
+/// command they are requested for (`ExecOptions::forwards`) runs.
+pub trait Transport: Send + Sync {
+    /// Start `command` in the remote user's shell
+    fn spawn(&self, command: &str, options: &ExecOptions) -> Result<RemoteChild, OffloadError>;
+
+    /// How rsync reaches the host, `None` if rsync cannot be used
+    fn rsync_remote(&self) -> Option<RsyncRemote>;
+
+    /// Copy files to the host. Failures are `OffloadError::Sync`.
+    fn sync_up(&self, request: &SyncRequest) -> Result<(), OffloadError> {
+        transfer::sync_up(self, request)
+    }
+
+    /// What `sync_up` would change on the host, which requires rsync
+    fn sync_up_dry_run(&self, request: &SyncRequest) -> Result<Vec<Change>, OffloadError> {
+        transfer::sync_up_dry_run(self, request)
+    }
+
+    /// Copy files from the host. Failures are `OffloadError::Artifacts`.
+    fn sync_down(&self, request: &SyncRequest) -> Result<(), OffloadError> {
+        transfer::sync_down(self, request)
+    }
+
+    /// Run `command` with `input` on its stdin and collect its output
+    fn output(&self, command: &str, input: &[u8]) -> Result<RemoteOutput, OffloadError> {
+        let mut child = self.spawn(command, &ExecOptions::default())?;
+        let mut stdin = child.stdin.take();
+        let stdout = child.stdout.take();
+        let stderr = child.stderr.take();
+
+        let (stdout, stderr) = std::thread::scope(|scope| {
+            let read = |reader: Option<Box<dyn Read + Send>>| {
+                scope.spawn(move || {
+                    let mut data = Vec::new();
+                    if let Some(mut reader) = reader {
+                        let _ = reader.read_to_end(&mut data);
+                    }
+                    data
+                })
+            };
+            let stdout = read(stdout);
+            let stderr = read(stderr);
+
+            // A command that doesn't read its input may exit before all of it is written
+            if let Some(stdin) = &mut stdin {
+                let _ = stdin.write_all(input);
+            }
+            drop(stdin);
+
+            (
+                stdout.join().unwrap_or_default(),
+                stderr.join().unwrap_or_default(),
+            )
+        });
+
+        Ok(RemoteOutput {
+            code: child.wait()?,
+            stdout,
+            stderr,
+        })
+    }
+}
+
+/// Connect to `host` with the configured SSH client. A `connect_timeout` is meant for
+/// probing hosts: it also disables interactive prompts for passwords or host keys.
+pub fn connect(
+    host: &HostConfig,
+    connect_timeout: Option<Duration>,
+) -> Result<Box<dyn Transport>, OffloadError> {
+    if host.local {
+        return Ok(Box::new(LocalTransport));
+    }
+
+    match host.ssh_client {
+        SshClient::Openssh => Ok(Box::new(OpensshTransport {
+            host: host.clone(),
+            connect_timeout,
+        })),
+        #[cfg(feature = "native-ssh")]
+        SshClient::Native => Ok(Box::new(crate::native_ssh::NativeTransport::connect(
+            host,
+            connect_timeout,
+        )?)),
+        #[cfg(not(feature = "native-ssh"))]
+        SshClient::Native => Err(OffloadError::Transport(
+            "ssh-client = \"native\" requires offload to be built with the `native-ssh` feature"
+                .to_string(),
+        )),
+    }
+}
+
+/// Runs commands with the `ssh` binary
+pub struct OpensshTransport {
+    host: HostConfig,
+    connect_timeout: Option<Duration>,
+}
+
+impl OpensshTransport {
+    fn ssh_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(timeout) = self.connect_timeout {
+            args.extend([
+                "-o".to_string(),
+                "BatchMode=yes".to_string(),
+                "-o".to_string(),
+                format!("ConnectTimeout={}", timeout.as_secs().max(1)),
+            ]);
+        }
+        args.extend(self.host.ssh_options());
+        args
+    }
+}
+
+impl Transport for OpensshTransport {
+    fn spawn(&self, command: &str, options: &ExecOptions) -> Result<RemoteChild, OffloadError> {
+        let mut ssh_cmd = Command::new("ssh");
+
+        // Force pseudo-terminal allocation for interactive programs, unless the output is
+        // parsed by another program and must not be mangled by the terminal
+        if options.tty {
+            ssh_cmd.arg("-t");
+        } else if options.interactive {
+            ssh_cmd.arg("-T");
+        }
+
+        if !options.forwards.is_empty() {
+            info!(
+                "Port forwarding: {}",
+                options
+                    .forwards
+                    .iter()
+                    .map(|forward| format!("{}:{}", forward.local, forward.remote))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+            for forward in options.forwards {
+                ssh_cmd
+                    .arg("-L")
+                    .arg(format!("{}:localhost:{}", forward.local, forward.remote));
+            }
+        }
+
+        ssh_cmd
+            .args(self.ssh_args())
+            .arg(&self.host.address)
+            .arg(command)
+            .stdin(if options.interactive {
+                Stdio::inherit()
+            } else {
+                Stdio::piped()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        let mut child: Child = ssh_cmd
+            .spawn()
+            .map_err(|e| OffloadError::Transport(format!("cannot execute ssh: {}", e)))?;
+        let stdin = child
+            .stdin
+            .take()
+            .map(|stdin| Box::new(stdin) as Box<dyn Write + Send>);
+        let stdout = child.stdout.take().expect("piped stdout");
+        let stderr = child.stderr.take().expect("piped stderr");
+
+        let command = command.to_string();
+        Ok(RemoteChild::new(
+            stdin,
+            Box::new(stdout),
+            Box::new(stderr),
+            move || {
+                let status = child
+                    .wait()
+                    .map_err(|e| OffloadError::Transport(format!("ssh failed: {}", e)))?;
+                match OffloadError::from_ssh_status(&command, status) {
+                    OffloadError::Remote { code, .. } => Ok(code),
+                    transport => Err(transport),
+                }
+            },
+        ))
+    }
+
+    fn rsync_remote(&self) -> Option<RsyncRemote> {
+        Some(RsyncRemote {
+            shell: Some(format!("ssh {}", shell_join(&self.ssh_args()))),
+            prefix: format!("{}:", self.host.address),
+        })
+    }
+}
+
+/// Runs commands on this machine, for `local:<dir>` hosts. Like over ssh, commands start
+/// in the home directory with a shell of their own.
+pub struct LocalTransport;
+
+impl Transport for LocalTransport {
+    fn spawn(&self, command: &str, options: &ExecOptions) -> Result<RemoteChild, OffloadError> {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(command);
+        if let Some(home) = std::env::var_os("HOME") {
+            cmd.current_dir(home);
+        }
+        // Set by rustup and cargo when offload runs as `cargo offload`, they would pin the
+        // toolchain and cargo binary the build uses
+        cmd.env_remove("RUSTUP_TOOLCHAIN").env_remove("CARGO");
+
+        for forward in options.forwards {
+            // The same port is reachable as it is, others go through a proxy
+            if forward.local != forward.remote {
+                forward_local_port(*forward)?;
+            }
+        }
+
+        let mut child = cmd
+            .stdin(if options.interactive {
+                Stdio::inherit()
+            } else {
+                Stdio::piped()
+            })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .map_err(|e| OffloadError::Transport(format!("cannot execute sh: {}", e)))?;
+        let stdin = child
+            .stdin
+            .take()
+            .map(|stdin| Box::new(stdin) as Box<dyn Write + Send>);
+        let stdout = child.stdout.take().expect("piped stdout");
+        let stderr = child.stderr.take().expect("piped stderr");
+
+        Ok(RemoteChild::new(
+            stdin,
+            Box::new(stdout),
+            Box::new(stderr),
+            move || {
+                let status = child
+                    .wait()
+                    .map_err(|e| OffloadError::Transport(format!("sh failed: {}", e)))?;
+                status
+                    .code()
+                    .ok_or_else(|| OffloadError::Transport(format!("local command {}", status)))
+            },
+        ))
+    }
+
+    fn rsync_remote(&self) -> Option<RsyncRemote> {
+        Some(RsyncRemote {
+            shell: None,
+            prefix: String::new(),
+        })
+    }
+}
+
+/// Accept connections on the local port in the background and pass them on to the
+/// remote port, for as long as offload runs
+fn forward_local_port(forward: Forward) -> Result<(), OffloadError> {
+    let listener = TcpListener::bind(("127.0.0.1", forward.local)).map_err(|e| {
+        OffloadError::Transport(format!("cannot listen on port {}: {}", forward.local, e))
+    })?;
+    info!("Port forwarding: {}:{}", forward.local, forward.remote);
+
+    std::thread::spawn(move || {
+        for client in listener.incoming().flatten() {
+            let Ok(server) = TcpStream::connect(("127.0.0.1", forward.remote)) else {
+                debug!("Nothing listens on port {}", forward.remote);
+                continue;
+            };
+            let (Ok(mut client_read), Ok(mut server_read)) =
+                (client.try_clone(), server.try_clone())
+            else {
+                continue;
+            };
+            let (mut client_write, mut server_write) = (client, server);
+            std::thread::spawn(move || {
+                let _ = io::copy(&mut client_read, &mut server_write);
+                let _ = server_write.shutdown(Shutdown::Write);
+            });
+            std::thread::spawn(move || {
+                let _ = io::copy(&mut server_read, &mut client_write);
+                let _ = client_write.shutdown(Shutdown::Write);
+            });
+        }
+    });
+
+    Ok(())
+}
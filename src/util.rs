@@ -1,4 +1,9 @@
-use std::{fs, path::Path};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
 
 use anyhow::{bail, Context, Result};
 use log::debug;
@@ -27,33 +32,138 @@ pub fn separate_run_args_from_raw(raw_args: &[String]) -> (Vec<String>, Vec<Stri
 }
 
 pub fn parse_flag(args: &[String], arg: &str) -> Result<Option<String>> {
-    if args.is_empty() {
-        return Ok(None);
-    }
-
-    for i in 0..(args.len() - 1) {
-        if let Some(a) = args[i].strip_prefix("--") {
-            let s = a.split("=").collect::<Vec<_>>();
-            if s.len() == 2 {
-                // found `--key=value` arg
-                if s[0] == arg {
-                    return Ok(Some(s[1].to_string()));
-                }
-            } else if a == arg {
-                // found regular `--key value` arg
-                let v = args[i + 1].clone();
-                if v.starts_with("-") {
-                    bail!("Invalid argument `--{a} {v}`");
-                }
-
-                return Ok(Some(v));
+    let mut iter = args.iter();
+    while let Some(current) = iter.next() {
+        let Some(a) = current.strip_prefix("--") else {
+            continue;
+        };
+
+        if let Some((key, value)) = a.split_once('=') {
+            // found `--key=value` arg
+            if key == arg {
+                return Ok(Some(value.to_string()));
+            }
+        } else if a == arg {
+            // found regular `--key value` arg
+            let Some(v) = iter.next() else {
+                bail!("Missing value for `--{a}`");
+            };
+            if v.starts_with("-") {
+                bail!("Invalid argument `--{a} {v}`");
             }
+
+            return Ok(Some(v.clone()));
         }
     }
 
     Ok(None)
 }
 
+/// Directory below `target/<triple>/` that cargo writes the selected profile to.
+///
+/// `dev` and `test` share `debug`, `bench` shares `release`. Custom profiles always get
+/// a directory of their own name, whichever profile they inherit from.
+pub fn profile_dir_name(args: &[String]) -> Result<String> {
+    let args: Vec<String> = args.iter().take_while(|a| *a != "--").cloned().collect();
+
+    let dir = match parse_flag(&args, "profile")?.as_deref() {
+        Some("dev" | "test") => "debug".to_string(),
+        Some("release" | "bench") => "release".to_string(),
+        Some(custom) => custom.to_string(),
+        None if args.iter().any(|a| a == "--release" || a == "-r") => "release".to_string(),
+        None => "debug".to_string(),
+    };
+
+    Ok(dir)
+}
+
+/// Run `cmd` with `input` as its stdin and wait for it to finish. A failing command may
+/// exit before reading all of its input, so write errors only count if it succeeded.
+pub fn output_with_input(cmd: &mut Command, input: &[u8]) -> io::Result<Output> {
+    let mut child = cmd.stdin(Stdio::piped()).spawn()?;
+    let written = match child.stdin.take() {
+        Some(mut stdin) => stdin.write_all(input),
+        None => Ok(()),
+    };
+    let output = child.wait_with_output()?;
+    if output.status.success() {
+        written?;
+    }
+
+    Ok(output)
+}
+
+/// Quote `arg` for a POSIX shell so that it is passed on as a single word, verbatim
+pub fn shell_quote(arg: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./_-".contains(c);
+    if !arg.is_empty() && arg.chars().all(is_safe) {
+        return arg.to_string();
+    }
+
+    // Single quotes preserve everything except a single quote, which is spliced in as '\''
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Quote every argument and join them into a single shell command line
+pub fn shell_join<S: AsRef<str>>(args: &[S]) -> String {
+    args.iter()
+        .map(|arg| shell_quote(arg.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Turn a `KEY=VALUE` argument into a shell variable assignment with the value quoted
+pub fn shell_env_assignment(var: &str) -> Result<String> {
+    let Some((name, value)) = var.split_once('=') else {
+        bail!("Invalid environment variable `{var}`, expected KEY=VALUE");
+    };
+
+    let valid_name = name
+        .chars()
+        .enumerate()
+        .all(|(i, c)| c == '_' || c.is_ascii_alphabetic() || (i > 0 && c.is_ascii_digit()));
+    if name.is_empty() || !valid_name {
+        bail!("Invalid environment variable name `{name}`");
+    }
+
+    Ok(format!("{}={}", name, shell_quote(value)))
+}
+
+/// Whether cargo is asked for machine readable output (`--message-format=json...`)
+pub fn uses_json_message_format(args: &[String]) -> bool {
+    let args: Vec<&str> = args
+        .iter()
+        .map(String::as_str)
+        .take_while(|arg| *arg != "--")
+        .collect();
+
+    args.iter().enumerate().any(|(i, arg)| {
+        let format = if *arg == "--message-format" {
+            args.get(i + 1).copied()
+        } else {
+            arg.strip_prefix("--message-format=")
+        };
+        format.is_some_and(|format| format.starts_with("json"))
+    })
+}
+
+/// Escape `s` for use inside a JSON string literal
+pub fn json_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if c.is_control() => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 pub fn detect_toolchain_from_cargo() -> Result<Option<String>> {
     let output = std::process::Command::new("cargo")
         .arg("--version")
@@ -75,7 +185,9 @@ pub fn detect_toolchain_from_cargo() -> Result<Option<String>> {
     Ok(None)
 }
 
-pub fn detect_toolchain_from_files() -> Result<Option<String>, Box<dyn std::error::Error>> {
+pub fn detect_toolchain_from_files(
+    dir: &Path,
+) -> Result<Option<String>, Box<dyn std::error::Error>> {
     #[derive(Deserialize)]
     struct RustToolchainToml {
         pub toolchain: Option<ToolchainConfig>,
@@ -87,9 +199,10 @@ pub fn detect_toolchain_from_files() -> Result<Option<String>, Box<dyn std::erro
     }
 
     // Try rust-toolchain.toml first
-    if Path::new("rust-toolchain.toml").exists() {
+    let toolchain_toml = dir.join("rust-toolchain.toml");
+    if toolchain_toml.exists() {
         let content =
-            fs::read_to_string("rust-toolchain.toml").context("Cannot open rust-toolchain.toml")?;
+            fs::read_to_string(&toolchain_toml).context("Cannot open rust-toolchain.toml")?;
         let parsed: RustToolchainToml =
             toml::from_str(&content).context("Cannot parse rust-toolchain.toml")?;
         if let Some(toolchain) = parsed.toolchain.and_then(|t| t.channel) {
@@ -99,8 +212,9 @@ pub fn detect_toolchain_from_files() -> Result<Option<String>, Box<dyn std::erro
     }
 
     // Try rust-toolchain file (plain text format)
-    if Path::new("rust-toolchain").exists() {
-        let content = fs::read_to_string("rust-toolchain").context("Cannot open rust-toolchain")?;
+    let toolchain_file = dir.join("rust-toolchain");
+    if toolchain_file.exists() {
+        let content = fs::read_to_string(&toolchain_file).context("Cannot open rust-toolchain")?;
         let toolchain = content.trim().to_string();
         if !toolchain.is_empty() {
             debug!("Detected toolchain from rust-toolchain: {}", toolchain);
@@ -123,3 +237,354 @@ pub fn format_duration(duration: std::time::Duration) -> String {
         format!("{}.{:03}s", seconds, millis)
     }
 }
+
+/// Message for a failure to start rsync, pointing at the sync method that works without it
+pub fn rsync_error(e: io::Error) -> String {
+    if e.kind() == io::ErrorKind::NotFound {
+        "rsync is not installed; install it or set `sync.method = \"builtin\"`".to_string()
+    } else {
+        format!("cannot execute rsync: {}", e)
+    }
+}
+
+/// Human readable size with binary units, e.g. `1.5 MiB`
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// 64-bit FNV-1a hash, stable across Rust versions and platforms
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// Replace everything but `[A-Za-z0-9._-]` so the name is safe to use in remote paths
+pub fn sanitize_path_component(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+pub fn local_user_name() -> String {
+    let user = std::env::var("USER")
+        .or_else(|_| std::env::var("USERNAME"))
+        .ok()
+        .or_else(|| {
+            let output = std::process::Command::new("id").arg("-un").output().ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        })
+        .filter(|user| !user.is_empty());
+
+    user.map(|user| sanitize_path_component(&user))
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+pub fn local_host_name() -> String {
+    std::env::var("HOSTNAME")
+        .ok()
+        .or_else(|| fs::read_to_string("/etc/hostname").ok())
+        .or_else(|| {
+            let output = std::process::Command::new("hostname").output().ok()?;
+            output
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&output.stdout).to_string())
+        })
+        .map(|host| host.trim().to_string())
+        .filter(|host| !host.is_empty())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+/// Whether a process with the given pid is running on this machine
+pub fn process_is_running(pid: u32) -> bool {
+    std::process::Command::new("kill")
+        .arg("-0")
+        .arg(pid.to_string())
+        .stderr(std::process::Stdio::null())
+        .status()
+        .map(|status| status.success())
+        .unwrap_or(true)
+}
+
+/// Name of the linked git worktree `dir` belongs to, `None` for the main worktree or outside of git
+pub fn git_worktree_name(dir: &Path) -> Option<String> {
+    let output = std::process::Command::new("git")
+        .arg("rev-parse")
+        .arg("--absolute-git-dir")
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    // Linked worktrees keep their git dir in `<repo>/.git/worktrees/<name>`
+    let git_dir = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    let parent = git_dir.parent()?;
+    if parent.file_name()? != "worktrees" {
+        return None;
+    }
+
+    git_dir
+        .file_name()
+        .map(|name| sanitize_path_component(&name.to_string_lossy()))
+}
+
+/// Remote counterpart of `local`, which lies below `local_base` mirrored at `remote_base`
+pub fn remote_path(remote_base: &str, local_base: &Path, local: &Path) -> String {
+    match local.strip_prefix(local_base) {
+        Ok(relative) if !relative.as_os_str().is_empty() => {
+            format!("{}/{}", remote_base, relative.display())
+        }
+        _ => remote_base.to_string(),
+    }
+}
+
+/// Path of the project relative to the remote base directory: `<user>/<folder>-<hash>`
+///
+/// The hash covers the local user and the canonical project path, so equally named
+/// checkouts and different users sharing a build host never end up in the same directory.
+pub fn remote_project_path(project_dir: &Path, include_worktree: bool) -> Result<String> {
+    let canonical = project_dir
+        .canonicalize()
+        .with_context(|| format!("Cannot resolve {}", project_dir.display()))?;
+    let folder_name = canonical
+        .file_name()
+        .map(|name| sanitize_path_component(&name.to_string_lossy()))
+        .unwrap_or_else(|| "root".to_string());
+    let user = local_user_name();
+
+    let mut key = format!("{}\0{}", user, canonical.display());
+    let mut name = folder_name;
+    if include_worktree {
+        if let Some(worktree) = git_worktree_name(&canonical) {
+            key.push_str(&format!("\0{}", worktree));
+            name = format!("{}@{}", name, worktree);
+        }
+    }
+
+    Ok(format!(
+        "{}/{}-{:016x}",
+        user,
+        name,
+        stable_hash(key.as_bytes())
+    ))
+}
+
+/// Empty directory below the system's temp dir, removed again by the caller
+#[cfg(test)]
+pub fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("offload-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What a POSIX shell makes of `command`, with `printf` showing each word on a line
+    fn shell_words(command: &str) -> Vec<String> {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf '%s\\n' {}", command))
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        let stdout = String::from_utf8(output.stdout).unwrap();
+        stdout
+            .strip_suffix('\n')
+            .unwrap()
+            .split('\n')
+            .map(str::to_string)
+            .collect()
+    }
+
+    const SPECIAL: &[&str] = &[
+        "it's",
+        "two words",
+        "$HOME",
+        "${PATH}",
+        "`id`",
+        "$(id)",
+        "\"quoted\"",
+        "back\\slash",
+        "semi;colon && or || pipe|",
+        "glob * ? [a]",
+        "~",
+        "#comment",
+        "'",
+        "''",
+        "grüße 你好",
+    ];
+
+    fn profile_dir(args: &[&str]) -> String {
+        let args: Vec<String> = args.iter().map(|a| a.to_string()).collect();
+        profile_dir_name(&args).unwrap()
+    }
+
+    #[test]
+    fn profile_dir_defaults_to_debug() {
+        assert_eq!(profile_dir(&[]), "debug");
+        assert_eq!(profile_dir(&["--bin", "app"]), "debug");
+    }
+
+    #[test]
+    fn profile_dir_of_release_flags() {
+        assert_eq!(profile_dir(&["-r"]), "release");
+        assert_eq!(profile_dir(&["--release"]), "release");
+        assert_eq!(profile_dir(&["--bin", "app", "--release"]), "release");
+        // Arguments for the program don't count
+        assert_eq!(profile_dir(&["--", "--release"]), "debug");
+        assert_eq!(profile_dir(&["--", "--profile", "custom"]), "debug");
+    }
+
+    #[test]
+    fn profile_dir_of_custom_profiles() {
+        assert_eq!(profile_dir(&["--profile", "fast"]), "fast");
+        assert_eq!(profile_dir(&["--profile=fast"]), "fast");
+        assert_eq!(
+            profile_dir(&["--profile=release-lto", "--bin", "app"]),
+            "release-lto"
+        );
+    }
+
+    #[test]
+    fn profile_dir_of_builtin_profiles() {
+        assert_eq!(profile_dir(&["--profile", "dev"]), "debug");
+        assert_eq!(profile_dir(&["--profile=test"]), "debug");
+        assert_eq!(profile_dir(&["--profile", "bench"]), "release");
+        assert_eq!(profile_dir(&["--profile=release"]), "release");
+    }
+
+    #[test]
+    fn profile_dir_rejects_missing_value() {
+        let args = vec!["--profile".to_string()];
+        assert!(profile_dir_name(&args).is_err());
+        let args = vec!["--profile".to_string(), "--release".to_string()];
+        assert!(profile_dir_name(&args).is_err());
+    }
+
+    #[test]
+    fn quote_keeps_safe_words() {
+        assert_eq!(shell_quote("cargo"), "cargo");
+        assert_eq!(
+            shell_quote("--target=x86_64-unknown-linux-gnu"),
+            "--target=x86_64-unknown-linux-gnu"
+        );
+        assert_eq!(
+            shell_quote("/home/user/src/my-project"),
+            "/home/user/src/my-project"
+        );
+        assert_eq!(shell_quote("user@host:22"), "user@host:22");
+    }
+
+    #[test]
+    fn quote_special_characters() {
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("$HOME"), "'$HOME'");
+        assert_eq!(shell_quote("`id`"), "'`id`'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("grüße"), "'grüße'");
+    }
+
+    #[test]
+    fn quote_empty_string() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_words(&shell_quote("")), vec![""]);
+    }
+
+    #[test]
+    fn quote_survives_the_shell() {
+        for arg in SPECIAL {
+            assert_eq!(shell_words(&shell_quote(arg)), vec![*arg], "{arg:?}");
+        }
+    }
+
+    #[test]
+    fn quote_newlines() {
+        let arg = "first\nsecond";
+        assert_eq!(shell_quote(arg), "'first\nsecond'");
+
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(format!("printf %s {}", shell_quote(arg)))
+            .output()
+            .unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), arg);
+    }
+
+    #[test]
+    fn join_keeps_arguments_apart() {
+        assert_eq!(
+            shell_join(&["cargo", "build", "--features", "a b"]),
+            "cargo build --features 'a b'"
+        );
+        assert_eq!(shell_join::<&str>(&[]), "");
+        assert_eq!(shell_join(&["", "x"]), "'' x");
+        assert_eq!(shell_words(&shell_join(SPECIAL)), SPECIAL);
+    }
+
+    #[test]
+    fn env_assignment_quotes_value() {
+        assert_eq!(
+            shell_env_assignment("RUST_LOG=debug").unwrap(),
+            "RUST_LOG=debug"
+        );
+        assert_eq!(shell_env_assignment("EMPTY=").unwrap(), "EMPTY=''");
+        assert_eq!(shell_env_assignment("A=b=c").unwrap(), "A=b=c");
+        assert_eq!(
+            shell_env_assignment("MSG=it's $HOME").unwrap(),
+            "MSG='it'\\''s $HOME'"
+        );
+        assert_eq!(shell_env_assignment("_X1=1").unwrap(), "_X1=1");
+    }
+
+    #[test]
+    fn env_assignment_survives_the_shell() {
+        for value in SPECIAL {
+            let assignment = shell_env_assignment(&format!("VALUE={}", value)).unwrap();
+            let output = Command::new("sh")
+                .arg("-c")
+                .arg(format!("{} && printf %s \"$VALUE\"", assignment))
+                .output()
+                .unwrap();
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn env_assignment_rejects_invalid_names() {
+        assert!(shell_env_assignment("NO_VALUE").is_err());
+        assert!(shell_env_assignment("=value").is_err());
+        assert!(shell_env_assignment("1X=value").is_err());
+        assert!(shell_env_assignment("A-B=value").is_err());
+        assert!(shell_env_assignment("A B=value").is_err());
+        assert!(shell_env_assignment("$(id)=value").is_err());
+        assert!(shell_env_assignment("ÄB=value").is_err());
+    }
+}
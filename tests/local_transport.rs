@@ -0,0 +1,281 @@
+//! Builds through a `local:<dir>` host, which runs the whole pipeline without ssh
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A cargo project and a host directory to build it in, removed when dropped
+struct Project {
+    dir: PathBuf,
+}
+
+impl Project {
+    fn new(name: &str) -> Project {
+        let dir =
+            std::env::temp_dir().join(format!("offload-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let project = Project { dir };
+
+        project.write(
+            "Cargo.toml",
+            &format!(
+                "[package]\nname = \"{}\"\nversion = \"0.1.0\"\nedition = \"2021\"\n\n[workspace]\n",
+                name
+            ),
+        );
+        project.write("src/main.rs", "fn main() {\n    println!(\"first\");\n}\n");
+        project.write(".offload.toml", "[sync]\nmethod = \"builtin\"\n");
+        project
+    }
+
+    fn root(&self) -> PathBuf {
+        self.dir.join("project")
+    }
+
+    fn write(&self, path: &str, content: &str) {
+        let path = self.root().join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    /// Run offload in the project with the host directory next to it
+    fn offload(&self, args: &[&str]) {
+        let host = self.local_host("host");
+        self.offload_configured(&[&["--host", host.as_str()], args].concat());
+    }
+
+    /// Run offload in the project on the hosts of its configuration
+    fn offload_configured(&self, args: &[&str]) {
+        let output = Command::new(env!("CARGO_BIN_EXE_offload"))
+            .arg("--target")
+            .arg(host_target())
+            .args(args)
+            .current_dir(self.root())
+            // Leave the user's configuration and defaults out of it
+            .env("XDG_CONFIG_HOME", self.dir.join("config"))
+            .env_remove("CARGO_OFFLOAD_HOST")
+            .env_remove("CARGO_OFFLOAD_PROFILE_HOST")
+            .env_remove("CARGO_TARGET_DIR")
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "offload {:?} failed: {}",
+            args,
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    /// A `local:` host in the directory `name` next to the project
+    fn local_host(&self, name: &str) -> String {
+        format!("local:{}", self.dir.join(name).display())
+    }
+
+    /// Content of the only cargo config on the host, which has to be `config.toml`
+    fn synced_cargo_config(&self) -> String {
+        let configs: Vec<PathBuf> = walk(&self.dir.join("host"))
+            .into_iter()
+            .filter(|path| path.parent().is_some_and(|dir| dir.ends_with(".cargo")))
+            .collect();
+        let [config] = configs.as_slice() else {
+            panic!("expected a single synced cargo config: {:?}", configs);
+        };
+        assert!(config.ends_with("config.toml"), "{}", config.display());
+        fs::read_to_string(config).unwrap()
+    }
+
+    fn artifact(&self, name: &str) -> PathBuf {
+        self.root()
+            .join("target/offload")
+            .join(host_target())
+            .join("debug")
+            .join(name)
+    }
+}
+
+impl Drop for Project {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Target triple of the toolchain running the tests, which the local host builds for
+fn host_target() -> String {
+    let output = Command::new("rustc").arg("-vV").output().unwrap();
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|line| line.strip_prefix("host: "))
+        .expect("rustc -vV prints the host triple")
+        .to_string()
+}
+
+fn run(binary: &Path) -> String {
+    let output = Command::new(binary).output().unwrap();
+    assert!(output.status.success());
+    String::from_utf8_lossy(&output.stdout).to_string()
+}
+
+#[test]
+fn build_copies_the_binary_back() {
+    let project = Project::new("roundtrip");
+    project.offload(&["build"]);
+
+    assert_eq!(run(&project.artifact("roundtrip")), "first\n");
+}
+
+#[test]
+fn build_syncs_changed_and_deleted_files() {
+    let project = Project::new("resync");
+    project.write("src/extra.rs", "pub const GREETING: &str = \"extra\";\n");
+    project.offload(&["build"]);
+
+    project.write("src/main.rs", "fn main() {\n    println!(\"second\");\n}\n");
+    fs::remove_file(project.root().join("src/extra.rs")).unwrap();
+    project.offload(&["build"]);
+
+    assert_eq!(run(&project.artifact("resync")), "second\n");
+    let synced: Vec<PathBuf> = walk(&project.dir.join("host"))
+        .into_iter()
+        .filter(|path| path.ends_with("src/extra.rs"))
+        .collect();
+    assert!(synced.is_empty(), "not deleted on the host: {:?}", synced);
+}
+
+#[test]
+fn build_syncs_directories_turned_into_files() {
+    let project = Project::new("reshape");
+    let main = "mod greeting;\nfn main() {\n    println!(\"{}\", greeting::TEXT);\n}\n";
+    project.write("src/main.rs", main);
+    project.write("src/greeting/mod.rs", "pub const TEXT: &str = \"dir\";\n");
+    project.write("assets/old/data.txt", "data");
+    project.offload(&["build"]);
+    assert_eq!(run(&project.artifact("reshape")), "dir\n");
+
+    fs::remove_dir_all(project.root().join("src/greeting")).unwrap();
+    fs::remove_dir_all(project.root().join("assets")).unwrap();
+    project.write("src/greeting.rs", "pub const TEXT: &str = \"file\";\n");
+    project.offload(&["build"]);
+    assert_eq!(run(&project.artifact("reshape")), "file\n");
+
+    let stale: Vec<PathBuf> = walk(&project.dir.join("host"))
+        .into_iter()
+        .filter(|path| path.ends_with("src/greeting") || path.ends_with("assets"))
+        .collect();
+    assert!(stale.is_empty(), "not removed on the host: {:?}", stale);
+
+    // And back from a file to a directory
+    fs::remove_file(project.root().join("src/greeting.rs")).unwrap();
+    project.write(
+        "src/greeting/mod.rs",
+        "pub const TEXT: &str = \"dir again\";\n",
+    );
+    project.offload(&["build"]);
+    assert_eq!(run(&project.artifact("reshape")), "dir again\n");
+}
+
+#[test]
+fn git_sync_filters_a_committed_cargo_config() {
+    let project = Project::new("gitconfig");
+    project.write(".offload.toml", "[sync]\nmethod = \"git\"\n");
+    project.write(
+        ".cargo/config",
+        "[alias]\nb = \"build\"\n\n[target.'cfg(all())']\nrunner = \"local-runner\"\n",
+    );
+    project.write(".gitignore", "target/\n");
+    let git = |args: &[&str]| {
+        let status = Command::new("git")
+            .args(["-c", "user.name=test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .current_dir(project.root())
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    };
+    git(&["init", "-q"]);
+    git(&["add", "."]);
+    git(&["commit", "-q", "-m", "initial"]);
+    project.offload(&["sync"]);
+    let content = project.synced_cargo_config();
+    assert!(content.contains("b = \"build\""), "{}", content);
+    assert!(!content.contains("runner"), "{}", content);
+
+    // An untracked config under the new name isn't sent as it is either
+    project.write(".cargo/config.toml", "[alias]\nt = \"test\"\n");
+    project.offload(&["sync"]);
+    let content = project.synced_cargo_config();
+    assert!(content.contains("t = \"test\""), "{}", content);
+}
+
+#[test]
+fn cargo_command_copies_configured_artifacts() {
+    let project = Project::new("commands");
+    project.write(
+        ".offload.toml",
+        "[sync]\nmethod = \"builtin\"\n\n[commands.doc]\nartifacts = [\"doc\"]\n",
+    );
+    let stale = project
+        .root()
+        .join("target/offload")
+        .join(host_target())
+        .join("doc/stale");
+    fs::create_dir_all(&stale).unwrap();
+
+    project.offload(&["cargo", "doc", "--no-deps"]);
+
+    let doc = stale.parent().unwrap();
+    assert!(doc.join("commands/index.html").is_file());
+    assert!(
+        !stale.exists(),
+        "the copied directory replaces the local one"
+    );
+}
+
+#[test]
+fn pool_host_is_reused_and_cleaned() {
+    let project = Project::new("pool");
+    let (first, second) = (project.local_host("first"), project.local_host("second"));
+    project.write(
+        ".offload.toml",
+        &format!(
+            "pool = [\"{}\", \"{}\"]\n\n[sync]\nmethod = \"builtin\"\n",
+            first, second
+        ),
+    );
+    let synced_to = |name: &str| {
+        walk(&project.dir.join(name))
+            .iter()
+            .any(|path| path.ends_with("src/main.rs"))
+    };
+
+    project.offload_configured(&["build"]);
+    let recorded = project.root().join("target/offload/pool-host");
+    let built_on = fs::read_to_string(&recorded).unwrap();
+    let other = if built_on.trim() == first {
+        "second"
+    } else {
+        assert_eq!(built_on.trim(), second);
+        "first"
+    };
+    assert!(!synced_to(other));
+
+    // Commands that don't build follow the recorded host instead of probing the pool
+    fs::write(&recorded, project.local_host(other)).unwrap();
+    project.offload_configured(&["sync"]);
+    assert!(synced_to(other));
+
+    project.offload_configured(&["clean"]);
+    assert!(!synced_to("first") && !synced_to("second"));
+    assert!(!recorded.exists());
+}
+
+fn walk(dir: &Path) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        let path = entry.path();
+        if path.is_dir() && !path.is_symlink() {
+            paths.extend(walk(&path));
+        }
+        paths.push(path);
+    }
+    paths
+}